@@ -0,0 +1,142 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::{sync::Arc, thread, time::Duration};
+
+use crate::{error::BackendError, types::AudioSpec, Opts};
+
+pub(crate) mod null;
+pub(crate) mod pulse;
+
+use null::{NullBackend, NullBackendSetup};
+use pulse::PulseBackend;
+
+/// Wakes the audio thread while it is blocked in [`OutputBackend::wait`].
+///
+/// Wakeups are not lost: waking a thread that isn't currently waiting makes its next
+/// wait return immediately.
+#[derive(Clone)]
+pub struct BackendWaker(Arc<dyn Fn() + Send + Sync>);
+
+impl BackendWaker {
+    pub fn new(wake: impl Fn() + Send + Sync + 'static) -> Self {
+        Self(Arc::new(wake))
+    }
+
+    /// A waker unparking the current thread, for backends waiting with
+    /// [`std::thread::park_timeout`].
+    pub fn unpark_current() -> Self {
+        let thread = thread::current();
+        Self::new(move || thread.unpark())
+    }
+
+    pub fn wake(&self) {
+        (self.0)()
+    }
+}
+
+impl std::fmt::Debug for BackendWaker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BackendWaker")
+    }
+}
+
+/// An audio output driven by the audio thread loop.
+///
+/// The backend decides when audio is needed and asks for it through the `render`
+/// callback given to [`OutputBackend::process`]. The callback always overwrites the
+/// whole buffer with interleaved samples in the backend's [`AudioSpec`].
+///
+/// Between calls to `process` the audio thread sleeps in [`OutputBackend::wait`], which
+/// returns when the backend needs servicing or when the thread is woken through the
+/// backend's [`BackendWaker`], e.g. because a message arrived.
+pub trait OutputBackend {
+    /// Audio spec of the samples accepted by the backend.
+    fn spec(&self) -> AudioSpec;
+
+    /// Service the backend, rendering any buffers it currently needs.
+    ///
+    /// Called repeatedly by the audio thread loop, so implementations should return
+    /// promptly when no audio is needed. An error shuts the audio thread down.
+    fn process(&mut self, render: &mut dyn FnMut(&mut [f32])) -> Result<(), BackendError>;
+
+    /// Block until the backend needs servicing, the waker from [`OutputBackend::waker`]
+    /// is woken, or `timeout` has passed. Returning early is allowed.
+    ///
+    /// The default implementation parks the thread for at most a couple of
+    /// milliseconds, for backends that can only be polled.
+    fn wait(&mut self, timeout: Duration) -> Result<(), BackendError> {
+        thread::park_timeout(timeout.min(Duration::from_millis(2)));
+        Ok(())
+    }
+
+    /// A waker interrupting [`OutputBackend::wait`], used from other threads.
+    ///
+    /// Called on the audio thread, which is the thread unparked by the default waker.
+    fn waker(&self) -> BackendWaker {
+        BackendWaker::unpark_current()
+    }
+
+    /// Pause or resume output while the mix is paused as a whole, so that the backend
+    /// can stop asking for silence.
+    ///
+    /// The render callback still only produces silence while paused, so backends are
+    /// free to ignore this.
+    fn set_paused(&mut self, _paused: bool) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Number of times the backend ran out of audio to play since it was started.
+    fn underruns(&self) -> u64 {
+        0
+    }
+
+    /// Time until audio rendered now is heard, if known.
+    fn latency(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Stop output and release any resources held by the backend.
+    fn shutdown(&mut self) {}
+}
+
+/// Function creating a user-provided backend on the audio thread.
+pub type CustomBackendFn =
+    Box<dyn FnOnce(&Opts) -> Result<Box<dyn OutputBackend>, BackendError> + Send>;
+
+/// Selects the output backend created by the audio thread.
+pub enum BackendSetup {
+    PulseAudio,
+    Null(NullBackendSetup),
+    Custom(CustomBackendFn),
+}
+
+impl std::fmt::Debug for BackendSetup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendSetup::PulseAudio => f.write_str("PulseAudio"),
+            BackendSetup::Null(setup) => write!(f, "Null({setup:?})"),
+            BackendSetup::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+pub(crate) fn make_backend(
+    setup: BackendSetup,
+    opts: &Opts,
+) -> Result<Box<dyn OutputBackend>, BackendError> {
+    match setup {
+        BackendSetup::PulseAudio => Ok(Box::new(PulseBackend::new(
+            opts.name(),
+            opts.spec(),
+            opts.buffer_size(),
+        )?)),
+        BackendSetup::Null(setup) => Ok(Box::new(NullBackend::from_setup(
+            setup,
+            opts.spec(),
+            opts.buffer_size(),
+        ))),
+        BackendSetup::Custom(make_fn) => make_fn(opts),
+    }
+}
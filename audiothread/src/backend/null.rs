@@ -0,0 +1,288 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::{
+    sync::{
+        mpsc::{channel, Receiver, Sender, TryRecvError},
+        Arc, OnceLock,
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    backend::OutputBackend,
+    error::{BackendError, ChannelDisconnectedError},
+    types::{AudioSpec, NonZeroNumFrames},
+};
+
+#[derive(Debug)]
+struct NullBackendRequest {
+    frames: NonZeroNumFrames,
+    response_tx: Sender<Vec<f32>>,
+}
+
+#[derive(Debug)]
+enum NullBackendClock {
+    RealTime {
+        output_tx: Option<Sender<Vec<f32>>>,
+    },
+    OnDemand {
+        request_rx: Receiver<NullBackendRequest>,
+        audio_thread: Arc<OnceLock<Thread>>,
+    },
+}
+
+/// Setup for a backend that renders audio without any sound server.
+#[derive(Debug)]
+pub struct NullBackendSetup {
+    clock: NullBackendClock,
+}
+
+impl NullBackendSetup {
+    /// Render buffers at the rate they would be played back, discarding the audio.
+    pub fn realtime() -> Self {
+        Self {
+            clock: NullBackendClock::RealTime { output_tx: None },
+        }
+    }
+
+    /// Render buffers at the rate they would be played back, sending the audio to
+    /// `output_tx`.
+    pub fn realtime_with_output(output_tx: Sender<Vec<f32>>) -> Self {
+        Self {
+            clock: NullBackendClock::RealTime {
+                output_tx: Some(output_tx),
+            },
+        }
+    }
+
+    /// Render buffers only when asked to through the returned handle.
+    pub fn on_demand() -> (Self, NullBackendHandle) {
+        let (request_tx, request_rx) = channel::<NullBackendRequest>();
+        let audio_thread = Arc::new(OnceLock::new());
+
+        (
+            Self {
+                clock: NullBackendClock::OnDemand {
+                    request_rx,
+                    audio_thread: Arc::clone(&audio_thread),
+                },
+            },
+            NullBackendHandle {
+                request_tx,
+                audio_thread,
+            },
+        )
+    }
+}
+
+/// Client handle for an on-demand null backend.
+#[derive(Debug, Clone)]
+pub struct NullBackendHandle {
+    request_tx: Sender<NullBackendRequest>,
+    audio_thread: Arc<OnceLock<Thread>>,
+}
+
+impl NullBackendHandle {
+    /// Render the given number of frames of output, blocking until the audio thread
+    /// has produced them.
+    pub fn render(&self, frames: NonZeroNumFrames) -> Result<Vec<f32>, ChannelDisconnectedError> {
+        let (response_tx, response_rx) = channel::<Vec<f32>>();
+
+        self.request_tx
+            .send(NullBackendRequest {
+                frames,
+                response_tx,
+            })
+            .map_err(|_| ChannelDisconnectedError)?;
+
+        if let Some(thread) = self.audio_thread.get() {
+            thread.unpark();
+        }
+
+        response_rx.recv().map_err(|_| ChannelDisconnectedError)
+    }
+}
+
+pub(crate) struct NullBackend {
+    spec: AudioSpec,
+    clock: NullBackendClock,
+    buffer: Vec<f32>,
+    started: Instant,
+    frames_rendered: u64,
+    underruns: u64,
+    paused: bool,
+}
+
+impl NullBackend {
+    pub fn from_setup(
+        setup: NullBackendSetup,
+        spec: AudioSpec,
+        buffer_size: NonZeroNumFrames,
+    ) -> Self {
+        // created on the audio thread, which is the one to wake up for requests
+        if let NullBackendClock::OnDemand { audio_thread, .. } = &setup.clock {
+            let _ = audio_thread.set(thread::current());
+        }
+
+        Self {
+            spec,
+            clock: setup.clock,
+            buffer: vec![0.0f32; buffer_size.get() * spec.channels.get() as usize],
+            started: Instant::now(),
+            frames_rendered: 0,
+            underruns: 0,
+            paused: false,
+        }
+    }
+
+    fn buffer_frames(&self) -> usize {
+        self.buffer.len() / self.spec.channels.get() as usize
+    }
+
+    fn frames_due(&self) -> u64 {
+        (self.started.elapsed().as_secs_f64() * self.spec.samplerate.get() as f64) as u64
+    }
+
+    /// Time until the next buffer is due when rendering in real time.
+    fn until_next_buffer(&self) -> Duration {
+        let next_buffer_end = self.frames_rendered + self.buffer_frames() as u64;
+        let due =
+            Duration::from_secs_f64(next_buffer_end as f64 / self.spec.samplerate.get() as f64);
+
+        due.saturating_sub(self.started.elapsed())
+    }
+}
+
+impl OutputBackend for NullBackend {
+    fn spec(&self) -> AudioSpec {
+        self.spec
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        Some(Duration::ZERO)
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<(), BackendError> {
+        match self.clock {
+            NullBackendClock::RealTime { .. } if !self.paused => {
+                thread::park_timeout(timeout.min(self.until_next_buffer()))
+            }
+            _ => thread::park_timeout(timeout),
+        }
+
+        Ok(())
+    }
+
+    fn underruns(&self) -> u64 {
+        self.underruns
+    }
+
+    fn set_paused(&mut self, paused: bool) -> Result<(), BackendError> {
+        // real time rendering continues from where it stopped, as if no time had passed
+        if !paused {
+            self.started = Instant::now()
+                - Duration::from_secs_f64(
+                    self.frames_rendered as f64 / self.spec.samplerate.get() as f64,
+                );
+        }
+
+        self.paused = paused;
+        Ok(())
+    }
+
+    fn process(&mut self, render: &mut dyn FnMut(&mut [f32])) -> Result<(), BackendError> {
+        match &self.clock {
+            NullBackendClock::RealTime { .. } if self.paused => (),
+
+            NullBackendClock::RealTime { output_tx } => {
+                let frames_due = self.frames_due();
+
+                // more than a whole buffer late means a real device would have run dry
+                if frames_due.saturating_sub(self.frames_rendered)
+                    >= 2 * self.buffer_frames() as u64
+                {
+                    self.underruns += 1;
+                }
+
+                while frames_due.saturating_sub(self.frames_rendered) >= self.buffer_frames() as u64
+                {
+                    render(&mut self.buffer);
+                    self.frames_rendered += self.buffer_frames() as u64;
+
+                    if let Some(tx) = output_tx {
+                        if tx.send(self.buffer.clone()).is_err() {
+                            return Err(BackendError("Output channel disconnected".to_string()));
+                        }
+                    }
+                }
+            }
+
+            NullBackendClock::OnDemand { request_rx, .. } => loop {
+                let request = match request_rx.try_recv() {
+                    Ok(request) => request,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        return Err(BackendError("Request channel disconnected".to_string()))
+                    }
+                };
+
+                let chans = self.spec.channels.get() as usize;
+                let mut output = Vec::with_capacity(request.frames.get() * chans);
+                let mut frames_left = request.frames.get();
+
+                while frames_left > 0 {
+                    let frames = std::cmp::min(frames_left, self.buffer_frames());
+                    let buffer = &mut self.buffer[..frames * chans];
+
+                    render(buffer);
+                    output.extend_from_slice(buffer);
+                    frames_left -= frames;
+                }
+
+                self.frames_rendered += request.frames.get() as u64;
+
+                if request.response_tx.send(output).is_err() {
+                    log::log!(log::Level::Warn, "Null backend client went away");
+                }
+            },
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_realtime_underruns() {
+        let spec = AudioSpec::new(1000, 1).unwrap();
+        let mut backend = NullBackend::from_setup(
+            NullBackendSetup::realtime(),
+            spec,
+            NonZeroNumFrames::new(100).unwrap(),
+        );
+        let mut buffers = 0;
+
+        // pretend to have been started long enough ago for some frames to be due
+        let mut process_at = |backend: &mut NullBackend, millis: u64| {
+            backend.started = Instant::now() - Duration::from_millis(millis);
+            backend.process(&mut |_| buffers += 1).unwrap();
+        };
+
+        process_at(&mut backend, 150);
+        assert_eq!(backend.underruns(), 0);
+
+        // two whole buffers late
+        process_at(&mut backend, 350);
+        assert_eq!(backend.underruns(), 1);
+
+        process_at(&mut backend, 380);
+        assert_eq!(backend.underruns(), 1);
+        assert_eq!(buffers, 3);
+    }
+}
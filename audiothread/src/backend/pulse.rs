@@ -0,0 +1,335 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::{
+    cell::Cell,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use libpulse_binding::{
+    context::{Context as PulseContext, FlagSet as PulseContextFlagSet},
+    def::{BufferAttr as PulseBufferAttr, Retval as PulseRetval},
+    mainloop::{
+        api::MainloopInnerType,
+        standard::{IterateResult, Mainloop as PulseMainloop, MainloopInternal},
+    },
+    sample::{Format as PulseSampleFormat, Spec as PulseSampleSpec},
+    stream::{FlagSet as PulseStreamFlagSet, Latency, SeekMode, Stream as PulseStream},
+    time::MicroSeconds,
+};
+
+use crate::{
+    backend::{BackendWaker, OutputBackend},
+    error::BackendError,
+    types::{AudioSpec, NonZeroNumFrames},
+};
+
+/// Pointer to the mainloop, for interrupting its poll from other threads.
+struct MainloopPtr(*mut MainloopInternal);
+
+// pa_mainloop_wakeup() is safe to call from any thread
+unsafe impl Send for MainloopPtr {}
+
+/// Wakes the mainloop until the backend is dropped, after which waking does nothing.
+struct MainloopWakeup(Mutex<Option<MainloopPtr>>);
+
+impl MainloopWakeup {
+    fn wake(&self) {
+        if let Some(MainloopPtr(mainloop)) = *self.0.lock().unwrap() {
+            unsafe { libpulse_sys::pa_mainloop_wakeup(mainloop) };
+        }
+    }
+}
+
+pub(crate) struct PulseBackend {
+    spec: AudioSpec,
+    framesize_bytes: usize,
+    bytes_requested: Rc<Cell<usize>>,
+    underruns: Rc<Cell<u64>>,
+    wakeup: Arc<MainloopWakeup>,
+
+    // boxed so that the addresses captured by the state callbacks stay valid, and
+    // declared in reverse order of creation so that they are dropped in that order.
+    stream: Box<PulseStream>,
+    context: Box<PulseContext>,
+    mainloop: PulseMainloop,
+}
+
+impl PulseBackend {
+    pub fn new(
+        name: &str,
+        spec: AudioSpec,
+        buffer_size: NonZeroNumFrames,
+    ) -> Result<Self, BackendError> {
+        let framesize_bytes: usize = 4 * spec.channels.get() as usize;
+
+        let pulse_spec = PulseSampleSpec {
+            format: PulseSampleFormat::FLOAT32NE,
+            rate: spec.samplerate.get(),
+            channels: spec.channels.get(),
+        };
+
+        if !pulse_spec.is_valid() {
+            return Err(BackendError(format!(
+                "Invalid PulseAudio sample spec {pulse_spec:?}"
+            )));
+        }
+
+        log::log!(
+            log::Level::Info,
+            "Using PulseAudio backend ({pulse_spec:?})"
+        );
+
+        let mut mainloop = PulseMainloop::new().ok_or(BackendError(
+            "Libpulse failed to allocate a mainloop".to_string(),
+        ))?;
+
+        let mut context = Box::new(PulseContext::new(&mainloop, name).ok_or(BackendError(
+            "Libpulse failed to allocate a context".to_string(),
+        ))?);
+
+        let context_csc: *const PulseContext = &*context;
+
+        let context_state_changed = move || {
+            log::log!(log::Level::Debug, "Context state changed: {:?}", unsafe {
+                (*context_csc).get_state()
+            })
+        };
+
+        context.set_state_callback(Some(Box::new(context_state_changed)));
+
+        context
+            .connect(None, PulseContextFlagSet::NOAUTOSPAWN, None)
+            .map_err(|e| BackendError(format!("Failed to connect to PulseAudio: {e}")))?;
+
+        log::log!(
+            log::Level::Info,
+            "Connected to server {:?}",
+            context.get_server()
+        );
+
+        wait_until(&mut mainloop, "connecting", || {
+            context.get_state() == libpulse_binding::context::State::Ready
+        })?;
+
+        let mut stream = Box::new(
+            PulseStream::new(&mut context, "My Stream", &pulse_spec, None).ok_or(BackendError(
+                "Libpulse failed to allocate a stream".to_string(),
+            ))?,
+        );
+
+        let stream_ssc: *const PulseStream = &*stream;
+
+        let stream_state_changed = move || {
+            log::log!(log::Level::Debug, "Stream state changed: {:?}", unsafe {
+                (*stream_ssc).get_state()
+            },);
+        };
+
+        stream.set_state_callback(Some(Box::new(stream_state_changed)));
+
+        let bytes_requested = Rc::new(Cell::new(0usize));
+        let bytes_requested_srw = Rc::clone(&bytes_requested);
+
+        // libpulse passes the total writable size, not the size added since last time
+        stream.set_write_callback(Some(Box::new(move |n: usize| bytes_requested_srw.set(n))));
+
+        let underruns = Rc::new(Cell::new(0u64));
+        let underruns_suc = Rc::clone(&underruns);
+
+        stream.set_underflow_callback(Some(Box::new(move || {
+            underruns_suc.set(underruns_suc.get() + 1);
+            log::log!(log::Level::Debug, "Stream underflow");
+        })));
+
+        stream
+            .connect_playback(
+                None,
+                Some(&PulseBufferAttr {
+                    maxlength: (buffer_size.get() * framesize_bytes) as u32,
+                    tlength: (buffer_size.get() * framesize_bytes) as u32,
+                    prebuf: 0,
+                    minreq: (buffer_size.get() * framesize_bytes) as u32,
+                    fragsize: 0,
+                }),
+                PulseStreamFlagSet::ADJUST_LATENCY
+                    | PulseStreamFlagSet::INTERPOLATE_TIMING
+                    | PulseStreamFlagSet::AUTO_TIMING_UPDATE,
+                None,
+                None,
+            )
+            .map_err(|e| BackendError(format!("Failed to connect stream for playback: {e}")))?;
+
+        wait_until(&mut mainloop, "creating a stream", || {
+            stream.get_state() == libpulse_binding::stream::State::Ready
+        })?;
+
+        let wakeup = Arc::new(MainloopWakeup(Mutex::new(Some(MainloopPtr(
+            mainloop._inner.get_ptr(),
+        )))));
+
+        Ok(Self {
+            spec,
+            framesize_bytes,
+            bytes_requested,
+            underruns,
+            wakeup,
+            stream,
+            context,
+            mainloop,
+        })
+    }
+
+    fn write(&mut self, n: usize, render: &mut dyn FnMut(&mut [f32])) {
+        debug_assert!(n.is_multiple_of(self.framesize_bytes));
+
+        match self.stream.begin_write(Some(n)) {
+            Ok(Some(buf)) => {
+                let (prefix, buf_f32, suffix) = unsafe { buf.align_to_mut::<f32>() };
+
+                debug_assert!(prefix.is_empty());
+                debug_assert!(suffix.is_empty());
+
+                render(buf_f32);
+
+                if let Err(e) = self.stream.write(buf, None, 0, SeekMode::Relative) {
+                    log::log!(log::Level::Warn, "Error writing to stream: {:?}", e);
+                }
+            }
+
+            Ok(None) => log::log!(
+                log::Level::Error,
+                "Stream ready for writing, but .begin_write failed to provide a buffer"
+            ),
+
+            Err(e) => log::log!(
+                log::Level::Error,
+                "Stream ready for writing, but .begin_write failed with error {:?}",
+                e
+            ),
+        }
+    }
+}
+
+fn wait_until(
+    mainloop: &mut PulseMainloop,
+    what: &str,
+    mut ready: impl FnMut() -> bool,
+) -> Result<(), BackendError> {
+    let timer = std::time::Instant::now();
+    let timeout = Duration::from_secs(5);
+
+    while !ready() {
+        match mainloop.iterate(true) {
+            IterateResult::Success(_) => (),
+            IterateResult::Quit(_) => {
+                return Err(BackendError(format!(
+                    "PulseAudio quit while audiothread was {what}"
+                )))
+            }
+            IterateResult::Err(e) => {
+                return Err(BackendError(format!(
+                    "PulseAudio error while audiothread was {what}: {e}"
+                )))
+            }
+        }
+
+        if timer.elapsed() > timeout {
+            return Err(BackendError(format!("Timed out while {what}")));
+        }
+    }
+
+    Ok(())
+}
+
+impl OutputBackend for PulseBackend {
+    fn spec(&self) -> AudioSpec {
+        self.spec
+    }
+
+    fn process(&mut self, render: &mut dyn FnMut(&mut [f32])) -> Result<(), BackendError> {
+        match self.mainloop.iterate(false) {
+            IterateResult::Success(_) => (),
+            IterateResult::Quit(_) => return Err(BackendError("PulseAudio quit".to_string())),
+            IterateResult::Err(e) => return Err(BackendError(format!("PulseAudio error: {e}"))),
+        }
+
+        let n = self.bytes_requested.replace(0);
+
+        if n > 0 {
+            self.write(n, render);
+        }
+
+        Ok(())
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<(), BackendError> {
+        let timeout = MicroSeconds(timeout.as_micros().min(i32::MAX as u128) as u64);
+
+        self.mainloop
+            .prepare(Some(timeout))
+            .and_then(|_| self.mainloop.poll())
+            .and_then(|_| self.mainloop.dispatch())
+            .map(|_| ())
+            .map_err(|e| BackendError(format!("PulseAudio error: {e}")))
+    }
+
+    fn waker(&self) -> BackendWaker {
+        let wakeup = Arc::clone(&self.wakeup);
+        BackendWaker::new(move || wakeup.wake())
+    }
+
+    fn underruns(&self) -> u64 {
+        self.underruns.get()
+    }
+
+    fn set_paused(&mut self, paused: bool) -> Result<(), BackendError> {
+        let done = Box::new(move |success: bool| {
+            if !success {
+                log::log!(
+                    log::Level::Error,
+                    "Failed to {} stream",
+                    if paused { "cork" } else { "uncork" }
+                );
+            }
+        });
+
+        // completion is only logged, the operation is carried out by the mainloop
+        if paused {
+            self.stream.cork(Some(done));
+        } else {
+            self.stream.uncork(Some(done));
+        }
+
+        Ok(())
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        match self.stream.get_latency() {
+            Ok(Latency::Positive(usecs)) => Some(Duration::from_micros(usecs.0)),
+            Ok(Latency::Negative(_)) => Some(Duration::ZERO),
+            Ok(Latency::None) | Err(_) => None,
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if let Err(e) = self.stream.disconnect() {
+            log::log!(log::Level::Error, "Failed to disconnect stream: {e}");
+        }
+
+        self.context.disconnect();
+
+        // is this needed/beneficial?
+        self.mainloop.quit(PulseRetval(0));
+    }
+}
+
+impl Drop for PulseBackend {
+    fn drop(&mut self) {
+        // the mainloop is freed along with the other fields, after this
+        *self.wakeup.0.lock().unwrap() = None;
+    }
+}
@@ -0,0 +1,152 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::collections::HashMap;
+
+use crate::{
+    effect::EffectChain,
+    error::MismatchedSpecError,
+    source::{voice::Voice, SourceGroup},
+    types::{AudioSpec, NumFrames, Quality},
+};
+
+/// Name of the bus that sources play on unless given another one.
+pub const DEFAULT_BUS: &str = "default";
+
+/// Multiply `buffer` by a gain ramping linearly from `from` to `to` over its frames.
+pub(crate) fn apply_gain_ramp(buffer: &mut [f32], channels: usize, from: f32, to: f32) {
+    if from == to {
+        if to != 1.0 {
+            buffer.iter_mut().for_each(|sample| *sample *= to);
+        }
+    } else {
+        let num_frames = buffer.len() / channels;
+
+        for (n, frame) in buffer.chunks_exact_mut(channels).enumerate() {
+            let gain = from + (to - from) * ((n + 1) as f32 / num_frames as f32);
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+}
+
+/// A named submix of sources with its own effects, gain, mute and solo.
+pub(crate) struct Bus {
+    groups: HashMap<AudioSpec, SourceGroup>,
+    effects: EffectChain,
+    gain: f32,
+    applied_gain: f32,
+    muted: bool,
+    soloed: bool,
+    buffer: Vec<f32>,
+}
+
+impl Bus {
+    pub fn new(output_spec: AudioSpec) -> Self {
+        Self {
+            groups: HashMap::new(),
+            effects: EffectChain::default(),
+            gain: 1.0,
+            applied_gain: 1.0,
+            muted: false,
+            soloed: false,
+            buffer: vec![
+                0.0;
+                output_spec.samplerate.get() as usize * output_spec.channels.get() as usize
+            ],
+        }
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &SourceGroup> {
+        self.groups.values()
+    }
+
+    pub fn groups_mut(&mut self) -> impl Iterator<Item = &mut SourceGroup> {
+        self.groups.values_mut()
+    }
+
+    pub fn effects_mut(&mut self) -> &mut EffectChain {
+        &mut self.effects
+    }
+
+    pub fn add_voice(
+        &mut self,
+        voice: Voice,
+        output_spec: AudioSpec,
+        conversion_quality: Quality,
+    ) -> Result<(), MismatchedSpecError> {
+        let spec = voice.spec();
+
+        self.groups
+            .entry(spec)
+            .or_insert_with(|| SourceGroup::new(spec, output_spec, conversion_quality))
+            .add_voice(voice)
+    }
+
+    /// Drop all sources right away, keeping the bus settings.
+    pub fn clear(&mut self) {
+        self.groups.clear();
+    }
+
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain.max(0.0);
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn set_soloed(&mut self, soloed: bool) {
+        self.soloed = soloed;
+    }
+
+    pub fn is_soloed(&self) -> bool {
+        self.soloed
+    }
+
+    /// Mix the bus into `out_buffer`, whose first frame is at `stream_time`. Sources
+    /// and effects keep playing while the bus is silenced by mute or by another bus
+    /// being soloed.
+    pub fn mix_to_given_spec(
+        &mut self,
+        out_spec: AudioSpec,
+        out_buffer: &mut [f32],
+        stream_time: NumFrames,
+        any_soloed: bool,
+    ) {
+        let audible = !self.muted && (self.soloed || !any_soloed);
+        let target_gain = if audible { self.gain } else { 0.0 };
+        let from_gain = std::mem::replace(&mut self.applied_gain, target_gain);
+
+        // effects keep running without sources to let delays and such ring out
+        if self.groups.is_empty() && self.effects.is_empty() {
+            return;
+        }
+
+        // only grows if asked to render more than a second at a time
+        if self.buffer.len() < out_buffer.len() {
+            self.buffer.resize(out_buffer.len(), 0.0);
+        }
+
+        let buffer = &mut self.buffer[..out_buffer.len()];
+        buffer.fill(0.0);
+
+        for group in self.groups.values_mut() {
+            group.mix_to_given_spec(out_spec, buffer, stream_time);
+        }
+
+        self.effects.process(out_spec, buffer);
+
+        apply_gain_ramp(
+            buffer,
+            out_spec.channels.get() as usize,
+            from_gain,
+            target_gain,
+        );
+
+        out_buffer
+            .iter_mut()
+            .zip(buffer.iter())
+            .for_each(|(output, sample)| *output += sample);
+    }
+}
@@ -0,0 +1,222 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::f32::consts::PI;
+
+use crate::{effect::Effect, types::AudioSpec};
+
+/// Response of a [`BiquadFilter`]. Gains are in dB.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterType {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+    Peak { gain_db: f32 },
+    LowShelf { gain_db: f32 },
+    HighShelf { gain_db: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Coefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coefficients {
+    /// Coefficients normalized by a0, following the Audio EQ Cookbook by Robert
+    /// Bristow-Johnson.
+    fn new(filter_type: FilterType, frequency: f32, q: f32, samplerate: f32) -> Self {
+        let w0 = 2.0 * PI * frequency.clamp(1.0, 0.49 * samplerate) / samplerate;
+        let (sin_w0, cos_w0) = w0.sin_cos();
+        let alpha = sin_w0 / (2.0 * q.max(0.01));
+
+        let (b0, b1, b2, a0, a1, a2) = match filter_type {
+            FilterType::LowPass => (
+                (1.0 - cos_w0) / 2.0,
+                1.0 - cos_w0,
+                (1.0 - cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::HighPass => (
+                (1.0 + cos_w0) / 2.0,
+                -(1.0 + cos_w0),
+                (1.0 + cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::BandPass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha),
+            FilterType::Notch => (
+                1.0,
+                -2.0 * cos_w0,
+                1.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::Peak { gain_db } => {
+                let a = 10f32.powf(gain_db / 40.0);
+
+                (
+                    1.0 + alpha * a,
+                    -2.0 * cos_w0,
+                    1.0 - alpha * a,
+                    1.0 + alpha / a,
+                    -2.0 * cos_w0,
+                    1.0 - alpha / a,
+                )
+            }
+            FilterType::LowShelf { gain_db } => {
+                let a = 10f32.powf(gain_db / 40.0);
+                let k = 2.0 * a.sqrt() * alpha;
+
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 + k),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - k),
+                    (a + 1.0) + (a - 1.0) * cos_w0 + k,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - k,
+                )
+            }
+            FilterType::HighShelf { gain_db } => {
+                let a = 10f32.powf(gain_db / 40.0);
+                let k = 2.0 * a.sqrt() * alpha;
+
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 + k),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - k),
+                    (a + 1.0) - (a - 1.0) * cos_w0 + k,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - k,
+                )
+            }
+        };
+
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+/// A second-order IIR filter, applied to each channel independently.
+#[derive(Debug, Clone)]
+pub struct BiquadFilter {
+    filter_type: FilterType,
+    frequency: f32,
+    q: f32,
+    spec: Option<AudioSpec>,
+    coefficients: Coefficients,
+
+    /// Transposed direct form II state, one pair per channel.
+    state: Vec<[f32; 2]>,
+}
+
+impl BiquadFilter {
+    /// Create a filter with cutoff or centre `frequency` in Hz. A `q` of 0.707 gives a
+    /// maximally flat low-pass or high-pass response.
+    pub fn new(filter_type: FilterType, frequency: f32, q: f32) -> Self {
+        Self {
+            filter_type,
+            frequency,
+            q,
+            spec: None,
+            coefficients: Coefficients::default(),
+            state: Vec::new(),
+        }
+    }
+}
+
+impl Effect for BiquadFilter {
+    fn prepare(&mut self, spec: AudioSpec) {
+        self.coefficients = Coefficients::new(
+            self.filter_type,
+            self.frequency,
+            self.q,
+            spec.samplerate.get() as f32,
+        );
+
+        self.state = vec![[0.0; 2]; spec.channels.get() as usize];
+        self.spec = Some(spec);
+    }
+
+    fn process(&mut self, spec: AudioSpec, buffer: &mut [f32]) {
+        if self.spec != Some(spec) {
+            self.prepare(spec);
+        }
+
+        let Coefficients { b0, b1, b2, a1, a2 } = self.coefficients;
+
+        for frame in buffer.chunks_exact_mut(self.state.len()) {
+            for (sample, [z1, z2]) in frame.iter_mut().zip(self.state.iter_mut()) {
+                let input = *sample;
+                let output = b0 * input + *z1;
+
+                *z1 = b1 * input - a1 * output + *z2;
+                *z2 = b2 * input - a2 * output;
+                *sample = output;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state.fill([0.0; 2]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settled_gain(filter_type: FilterType, frequency: f32, signal: impl Fn(usize) -> f32) -> f32 {
+        let spec = AudioSpec::new(48000, 2).unwrap();
+        let mut filter = BiquadFilter::new(filter_type, frequency, 0.707);
+
+        let mut buffer = (0..48000)
+            .flat_map(|n| [signal(n), signal(n)])
+            .collect::<Vec<_>>();
+
+        filter.process(spec, &mut buffer);
+
+        // peak over the last tenth of a second, after the filter has settled
+        buffer[2 * 43200..]
+            .iter()
+            .fold(0.0f32, |acc, x| acc.max(x.abs()))
+    }
+
+    fn sine(frequency: f32) -> impl Fn(usize) -> f32 {
+        move |n| (2.0 * PI * frequency * n as f32 / 48000.0).sin()
+    }
+
+    #[test]
+    fn test_low_pass() {
+        assert!((settled_gain(FilterType::LowPass, 1000.0, |_| 1.0) - 1.0).abs() < 1e-3);
+        assert!(settled_gain(FilterType::LowPass, 1000.0, sine(100.0)) > 0.99);
+        assert!(settled_gain(FilterType::LowPass, 1000.0, sine(10000.0)) < 0.02);
+    }
+
+    #[test]
+    fn test_high_pass() {
+        assert!(settled_gain(FilterType::HighPass, 1000.0, |_| 1.0) < 1e-3);
+        assert!(settled_gain(FilterType::HighPass, 1000.0, sine(100.0)) < 0.02);
+        assert!(settled_gain(FilterType::HighPass, 1000.0, sine(10000.0)) > 0.99);
+    }
+
+    #[test]
+    fn test_peak() {
+        let gain = settled_gain(FilterType::Peak { gain_db: 6.0 }, 1000.0, sine(1000.0));
+        assert!((gain - 10f32.powf(6.0 / 20.0)).abs() < 0.01);
+    }
+}
@@ -0,0 +1,96 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::time::Duration;
+
+use crate::{effect::Effect, types::AudioSpec};
+
+/// An echo, feeding the delayed signal back into the delay line.
+#[derive(Debug, Clone)]
+pub struct FeedbackDelay {
+    delay: Duration,
+    feedback: f32,
+    mix: f32,
+    spec: Option<AudioSpec>,
+
+    /// Interleaved delay line holding one delay's worth of frames.
+    line: Vec<f32>,
+    position: usize,
+}
+
+impl FeedbackDelay {
+    /// Create a delay with linear `feedback` (limited to below 1.0), and a wet/dry `mix`
+    /// from 0.0 (only the input) to 1.0 (only the echoes).
+    pub fn new(delay: Duration, feedback: f32, mix: f32) -> Self {
+        Self {
+            delay,
+            feedback: feedback.clamp(0.0, 0.99),
+            mix: mix.clamp(0.0, 1.0),
+            spec: None,
+            line: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Effect for FeedbackDelay {
+    fn prepare(&mut self, spec: AudioSpec) {
+        let frames =
+            ((self.delay.as_secs_f64() * spec.samplerate.get() as f64).round() as usize).max(1);
+
+        self.line = vec![0.0; frames * spec.channels.get() as usize];
+        self.position = 0;
+        self.spec = Some(spec);
+    }
+
+    fn process(&mut self, spec: AudioSpec, buffer: &mut [f32]) {
+        if self.spec != Some(spec) {
+            self.prepare(spec);
+        }
+
+        let chans = spec.channels.get() as usize;
+
+        for frame in buffer.chunks_exact_mut(chans) {
+            let delayed = &mut self.line[self.position..self.position + chans];
+
+            for (sample, delayed) in frame.iter_mut().zip(delayed.iter_mut()) {
+                let input = *sample;
+                let echo = *delayed;
+
+                *delayed = input + echo * self.feedback;
+                *sample = input * (1.0 - self.mix) + echo * self.mix;
+            }
+
+            self.position = (self.position + chans) % self.line.len();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.line.fill(0.0);
+        self.position = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feedback_delay() {
+        let spec = AudioSpec::new(1000, 1).unwrap();
+        let mut delay = FeedbackDelay::new(Duration::from_millis(3), 0.5, 0.5);
+
+        let mut buffer = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        delay.process(spec, &mut buffer);
+
+        assert_eq!(buffer, [0.5, 0.0, 0.0, 0.5, 0.0, 0.0, 0.25, 0.0]);
+
+        delay.reset();
+
+        let mut buffer = [0.0; 8];
+        delay.process(spec, &mut buffer);
+
+        assert_eq!(buffer, [0.0; 8]);
+    }
+}
@@ -0,0 +1,135 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use crate::types::{AudioSpec, EffectId};
+
+pub(crate) mod biquad;
+pub(crate) mod delay;
+
+/// An audio effect processing the mix of a bus or of the master output.
+pub trait Effect: Send {
+    /// Called with the spec of the audio to be processed when the effect is inserted,
+    /// before any call to [`Effect::process`]. Buffers should be allocated here rather
+    /// than while processing.
+    fn prepare(&mut self, _spec: AudioSpec) {}
+
+    /// Process a block of interleaved samples in place.
+    fn process(&mut self, spec: AudioSpec, buffer: &mut [f32]);
+
+    /// Clear any internal state such as delay lines, e.g when the effect is taken out
+    /// of bypass.
+    fn reset(&mut self) {}
+}
+
+impl std::fmt::Debug for dyn Effect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Effect")
+    }
+}
+
+struct InsertedEffect {
+    id: EffectId,
+    effect: Box<dyn Effect>,
+    bypassed: bool,
+}
+
+/// Effects applied in order of insertion.
+#[derive(Default)]
+pub(crate) struct EffectChain {
+    effects: Vec<InsertedEffect>,
+}
+
+impl EffectChain {
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    pub fn insert(&mut self, id: EffectId, mut effect: Box<dyn Effect>, spec: AudioSpec) {
+        effect.prepare(spec);
+
+        self.effects.push(InsertedEffect {
+            id,
+            effect,
+            bypassed: false,
+        });
+    }
+
+    /// Remove an effect, returning whether it was part of this chain.
+    pub fn remove(&mut self, id: EffectId) -> bool {
+        let len = self.effects.len();
+        self.effects.retain(|inserted| inserted.id != id);
+        self.effects.len() != len
+    }
+
+    /// Bypass an effect or take it out of bypass, returning whether it was part of this
+    /// chain.
+    pub fn set_bypassed(&mut self, id: EffectId, bypassed: bool) -> bool {
+        match self.effects.iter_mut().find(|inserted| inserted.id == id) {
+            Some(inserted) => {
+                // don't let stale state such as old delay lines play when resuming
+                if inserted.bypassed && !bypassed {
+                    inserted.effect.reset();
+                }
+
+                inserted.bypassed = bypassed;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn process(&mut self, spec: AudioSpec, buffer: &mut [f32]) {
+        self.effects
+            .iter_mut()
+            .filter(|inserted| !inserted.bypassed)
+            .for_each(|inserted| inserted.effect.process(spec, buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Add(f32);
+
+    impl Effect for Add {
+        fn process(&mut self, _spec: AudioSpec, buffer: &mut [f32]) {
+            buffer.iter_mut().for_each(|sample| *sample += self.0);
+        }
+    }
+
+    struct Double;
+
+    impl Effect for Double {
+        fn process(&mut self, _spec: AudioSpec, buffer: &mut [f32]) {
+            buffer.iter_mut().for_each(|sample| *sample *= 2.0);
+        }
+    }
+
+    #[test]
+    fn test_effect_chain() {
+        let spec = AudioSpec::new(48000, 1).unwrap();
+        let mut chain = EffectChain::default();
+        let (add, double) = (EffectId::unique(), EffectId::unique());
+
+        chain.insert(add, Box::new(Add(1.0)), spec);
+        chain.insert(double, Box::new(Double), spec);
+
+        let mut buffer = [1.0, 2.0];
+        chain.process(spec, &mut buffer);
+        assert_eq!(buffer, [4.0, 6.0]);
+
+        assert!(chain.set_bypassed(add, true));
+        chain.process(spec, &mut buffer);
+        assert_eq!(buffer, [8.0, 12.0]);
+
+        assert!(chain.remove(double));
+        assert!(!chain.remove(double));
+        assert!(!chain.set_bypassed(double, true));
+
+        assert!(chain.set_bypassed(add, false));
+        chain.process(spec, &mut buffer);
+        assert_eq!(buffer, [9.0, 13.0]);
+    }
+}
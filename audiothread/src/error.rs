@@ -50,10 +50,6 @@ pub struct SymphoniaError(#[from] symphonia::core::errors::Error);
 #[error("Symphonia source error: {0}")]
 pub struct SymphoniaSourceError(pub String);
 
-#[derive(Debug, ThisError)]
-#[error("Invalid buffer size: {0}")]
-pub struct InvalidBufferSizeError(pub String);
-
 #[derive(Debug, ThisError)]
 #[error("Mismatched spec")]
 pub struct MismatchedSpecError;
@@ -65,3 +61,13 @@ pub struct ValueOutOfRangeError(pub String);
 #[derive(Debug, ThisError)]
 #[error("Channel disconnected")]
 pub struct ChannelDisconnectedError;
+
+#[derive(Debug, ThisError)]
+#[error("Backend error: {0}")]
+pub struct BackendError(pub String);
+
+#[derive(Debug, ThisError)]
+#[error("Resampler error: {0}")]
+pub struct ResamplerError(pub String);
+
+error_enum!(StartupError = { BackendError, ChannelDisconnectedError });
@@ -3,50 +3,192 @@
 // Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
 
 use std::{
-    cell::RefCell,
     collections::HashMap,
-    rc::Rc,
-    sync::mpsc::{self, RecvTimeoutError, Sender},
+    path::PathBuf,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender},
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
-use libpulse_binding::{
-    context::{Context as PulseContext, FlagSet as PulseContextFlagSet},
-    def::{BufferAttr as PulseBufferAttr, Retval as PulseRetval},
-    mainloop::standard::Mainloop as PulseMainloop,
-    sample::{Format as PulseSampleFormat, Spec as PulseSampleSpec},
-    stream::{FlagSet as PulseStreamFlagSet, SeekMode, Stream as PulseStream},
-};
-
+mod backend;
+mod bus;
+mod effect;
 mod error;
 mod ext;
+mod limiter;
+mod meter;
+mod mixer;
+mod recorder;
+mod resampler;
 mod source;
+mod subscription;
+mod tap;
 mod types;
 
 use crate::{
-    error::ChannelDisconnectedError,
-    source::{pulled::PulledSource, Source, SourceGroup, SourceOps},
+    backend::make_backend,
+    mixer::Mixer,
+    recorder::Recorder,
+    source::{pulled::PulledSource, Source},
+    subscription::ActiveSubscription,
 };
 
 pub use crate::{
+    backend::{
+        null::{NullBackendHandle, NullBackendSetup},
+        BackendSetup, BackendWaker, CustomBackendFn, OutputBackend,
+    },
+    bus::DEFAULT_BUS,
+    effect::{
+        biquad::{BiquadFilter, FilterType},
+        delay::FeedbackDelay,
+        Effect,
+    },
+    error::{
+        BackendError, ChannelDisconnectedError, MismatchedSpecError, StartupError,
+        SymphoniaSourceError,
+    },
+    limiter::Limiter,
+    meter::{Levels, MeterReading, MeteringSetup},
+    recorder::RecordingFormat,
     source::{
+        buffer::BufferSource,
         pulled::{PulledSourcePullReply, PulledSourcePullRequest, PulledSourceSetup},
-        symphonia::SymphoniaSource,
+        queue::{QueueItem, QueueSource},
+        symphonia::{Looping, SymphoniaSource},
+        voice::{PlayOpts, SourceInfo},
     },
     source::{SourceMatcher, SourceType},
-    types::{AudioSpec, NonZeroNumFrames, NumChannels, NumFrames, Quality, Samplerate},
+    subscription::Subscription,
+    tap::{TapReceiver, TapSetup},
+    types::{
+        AudioSpec, EffectId, FadeLength, NonZeroNumFrames, NumChannels, NumFrames, PlaybackRate,
+        Quality, Samplerate, SourceId, StreamState, VoiceStealing,
+    },
 };
 
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
 pub enum Message {
     Shutdown,
-    DropAll,
-    DropAllMatching(SourceMatcher),
-    PlaySymphoniaSource(SymphoniaSource),
-    CreatePulledSource(PulledSourceSetup),
+
+    /// Drop all sources, optionally fading them out first.
+    DropAll(Option<FadeLength>),
+
+    DropAllMatching(SourceMatcher, Option<FadeLength>),
+    PlaySymphoniaSource(SourceId, SymphoniaSource, PlayOpts),
+    CreatePulledSource(SourceId, PulledSourceSetup, PlayOpts),
+    PlayBufferSource(SourceId, BufferSource, PlayOpts),
+    PlayQueueSource(SourceId, QueueSource, PlayOpts),
+
+    /// Add an item to the end of a playing queue. The item must have the spec of the
+    /// queue.
+    AppendToQueue {
+        queue: SourceId,
+        item: SourceId,
+        source: QueueItem,
+    },
+
+    /// Drop the items of a playing queue that are yet to be started.
+    ClearQueue(SourceId),
+
+    StopSource(SourceId, Option<FadeLength>),
+
+    /// Pause the sources matched, or the whole output if `None`. Paused sources keep
+    /// their positions, and while the whole output is paused no source plays, including
+    /// sources started in the meantime.
+    Pause(Option<SourceMatcher>),
+
+    /// Resume the sources matched, or the whole output if `None`. Resuming the output
+    /// does not resume sources paused on their own.
+    Resume(Option<SourceMatcher>),
+
+    PauseSource(SourceId),
+    ResumeSource(SourceId),
+    SetSourceGain(SourceId, f32),
+    SetSourcePan(SourceId, f32),
+
+    /// Change the playback rate of a source, and with it the pitch.
+    SetSourceRate(SourceId, PlaybackRate),
+    SetMasterVolume(f32),
+
+    /// Set the linear gain of the named bus, created if needed.
+    SetBusGain(String, f32),
+
+    /// Mute or unmute the named bus, created if needed. Sources on a muted bus keep
+    /// playing silently.
+    SetBusMute(String, bool),
+
+    /// Solo or unsolo the named bus, created if needed. While any bus is soloed only
+    /// soloed buses are heard.
+    SetBusSolo(String, bool),
+
+    /// Insert an effect last in the chain of the named bus, created if needed, or of the
+    /// master output if `None`. Bus effects process the mix of the bus before its gain is
+    /// applied, master effects process the final mix before the master volume and
+    /// limiter.
+    InsertEffect(Option<String>, EffectId, Box<dyn Effect>),
+
+    RemoveEffect(EffectId),
+
+    /// Bypass an effect or take it out of bypass. Effects are reset when taken out of
+    /// bypass.
+    SetEffectBypass(EffectId, bool),
+
+    /// Get the largest master limiter gain reduction since the previous request, in dB.
+    GetGainReduction(Sender<f32>),
+
+    /// Start publishing meter readings, or stop if `None`.
+    SetMetering(Option<MeteringSetup>),
+
+    GetSourceInfo(SourceId, Sender<Option<SourceInfo>>),
+
+    /// Start publishing the info of all playing sources, or stop if `None`.
+    SetSourceInfoSubscription(Option<Subscription<HashMap<SourceId, SourceInfo>>>),
+
     GetOutputSpec(Sender<AudioSpec>),
+
+    /// Copy the final mix into a tap, until its [`TapReceiver`] is dropped.
+    AddTap(TapSetup),
+
+    /// Record the final mix to a WAV file, stopping any recording already in progress.
+    /// The outcome is reported with a [`StatusMessage`] once the file is finished.
+    StartRecording {
+        path: PathBuf,
+        format: RecordingFormat,
+    },
+
+    StopRecording,
+
+    /// Get the output stream time, the number of output frames rendered so far. Frames
+    /// are not counted while the output is paused.
+    GetStreamTime(Sender<NumFrames>),
+
+    /// Get the number of times the output backend has run out of audio to play.
+    GetUnderruns(Sender<u64>),
+}
+
+/// Status messages published by a running audio thread.
+#[derive(Debug, Clone)]
+pub enum StatusMessage {
+    /// The output backend failed and the audio thread is shutting down.
+    BackendFailed(String),
+
+    /// The audio thread has shut down.
+    ShutDown,
+
+    /// A recording has been stopped and its file finalized.
+    RecordingFinished(PathBuf),
+
+    /// A recording was aborted because of an error.
+    RecordingFailed(String),
+
+    /// An item of a queue has been played to its end.
+    QueueItemFinished { queue: SourceId, item: SourceId },
+
+    /// A source was not started, as the voice limit had been reached and new sources
+    /// are rejected with [`VoiceStealing::RejectNew`].
+    SourceRejected(SourceId),
 }
 
 #[derive(Debug)]
@@ -55,6 +197,10 @@ pub struct Opts {
     spec: AudioSpec,
     conversion_quality: Quality,
     buffer_size: NonZeroNumFrames,
+    master_volume: f32,
+    limiter: Limiter,
+    voice_limit: Option<(usize, VoiceStealing)>,
+    backend: BackendSetup,
 }
 
 impl Default for Opts {
@@ -64,6 +210,10 @@ impl Default for Opts {
             spec: AudioSpec::new(48000, 2).unwrap(),
             conversion_quality: Quality::Medium,
             buffer_size: 2048.try_into().unwrap(),
+            master_volume: 1.0,
+            limiter: Limiter::Off,
+            voice_limit: None,
+            backend: BackendSetup::PulseAudio,
         }
     }
 }
@@ -80,6 +230,10 @@ impl Opts {
             spec,
             conversion_quality,
             buffer_size,
+            master_volume: 1.0,
+            limiter: Limiter::Off,
+            voice_limit: None,
+            backend: BackendSetup::PulseAudio,
         }
     }
 
@@ -107,6 +261,61 @@ impl Opts {
             ..self
         }
     }
+
+    /// Set the initial linear master volume.
+    pub fn with_master_volume(self, master_volume: f32) -> Self {
+        Opts {
+            master_volume: master_volume.max(0.0),
+            ..self
+        }
+    }
+
+    /// Set the limiter applied to the final mix. Ceilings below -80 dBFS are raised
+    /// to -80 dBFS.
+    pub fn with_limiter(self, limiter: Limiter) -> Self {
+        Opts {
+            limiter: limiter.clamped(),
+            ..self
+        }
+    }
+
+    /// Limit the number of sources playing at once to `max_voices` (at least one),
+    /// making way for new sources as chosen by `stealing`. Sources made way for are
+    /// faded out quickly rather than cut.
+    pub fn with_voice_limit(self, max_voices: usize, stealing: VoiceStealing) -> Self {
+        Opts {
+            voice_limit: Some((max_voices.max(1), stealing)),
+            ..self
+        }
+    }
+
+    pub fn with_backend(self, backend: BackendSetup) -> Self {
+        Opts { backend, ..self }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.stream_name
+    }
+
+    pub fn spec(&self) -> AudioSpec {
+        self.spec
+    }
+
+    pub fn conversion_quality(&self) -> Quality {
+        self.conversion_quality
+    }
+
+    pub fn buffer_size(&self) -> NonZeroNumFrames {
+        self.buffer_size
+    }
+
+    pub fn limiter(&self) -> Limiter {
+        self.limiter
+    }
+
+    pub fn voice_limit(&self) -> Option<(usize, VoiceStealing)> {
+        self.voice_limit
+    }
 }
 
 fn recv_all(
@@ -138,200 +347,163 @@ fn recv_all(
     }
 }
 
-pub fn spawn(rx: mpsc::Receiver<Message>, opts: Option<Opts>) -> JoinHandle<()> {
-    thread::spawn(move || threadloop(rx, opts))
-}
-
-fn threadloop(rx: mpsc::Receiver<Message>, opts: Option<Opts>) {
-    let opts = opts.unwrap_or_default();
-
-    let conversion_quality = opts.conversion_quality;
-    let output_spec = opts.spec;
-    let framesize_bytes: usize = 4 * output_spec.channels.get() as usize;
-
-    let pulse_spec = PulseSampleSpec {
-        format: PulseSampleFormat::FLOAT32NE,
-        rate: output_spec.samplerate.get(),
-        channels: output_spec.channels.get(),
-    };
-
-    assert!(pulse_spec.is_valid());
+/// Forward messages from the client to the audio thread, waking it for each one.
+///
+/// Returns once either end has gone away. A disconnected client is noticed by the
+/// audio thread through the dropped `tx`, so it is woken one last time.
+fn forward_messages(rx: Receiver<Message>, tx: Sender<Message>, waker: BackendWaker) {
+    while let Ok(message) = rx.recv() {
+        if tx.send(message).is_err() {
+            return;
+        }
 
-    log::log!(
-        log::Level::Info,
-        "Audiothread starting up ({pulse_spec:?}, {conversion_quality:?})"
-    );
+        waker.wake();
+    }
 
-    let mut pa_mainloop =
-        PulseMainloop::new().expect("Libpulse should be able to allocate a mainloop");
+    drop(tx);
+    waker.wake();
+}
 
-    let mut pa_context = PulseContext::new(&pa_mainloop, &opts.stream_name)
-        .expect("Libpulse should be able to alloate a context");
+/// Handle to a successfully started audio thread.
+#[derive(Debug)]
+pub struct AudioThreadHandle {
+    join_handle: JoinHandle<()>,
+    status_rx: Receiver<StatusMessage>,
+}
 
-    let pa_context_raw: *mut PulseContext = &mut pa_context;
-    let pa_context_csc = pa_context_raw;
+impl AudioThreadHandle {
+    /// Channel on which the audio thread publishes [`StatusMessage`]s.
+    pub fn status_rx(&self) -> &Receiver<StatusMessage> {
+        &self.status_rx
+    }
 
-    let sourcegroups: Rc<RefCell<HashMap<AudioSpec, SourceGroup>>> =
-        Rc::new(RefCell::new(HashMap::new()));
+    pub fn is_finished(&self) -> bool {
+        self.join_handle.is_finished()
+    }
 
-    let sourcegroups_srw = Rc::clone(&sourcegroups);
+    pub fn join(self) -> thread::Result<()> {
+        self.join_handle.join()
+    }
+}
 
-    let context_state_changed = move || {
-        log::log!(log::Level::Debug, "Context state changed: {:?}", unsafe {
-            (*pa_context_csc).get_state()
-        })
-    };
+/// Spawn an audio thread receiving messages on `rx`.
+///
+/// Blocks until the output backend has been started, so that any startup failure is
+/// reported here rather than inside the thread.
+pub fn spawn(
+    rx: mpsc::Receiver<Message>,
+    opts: Option<Opts>,
+) -> Result<AudioThreadHandle, StartupError> {
+    let (startup_tx, startup_rx) = mpsc::sync_channel::<Result<(), BackendError>>(1);
+    let (status_tx, status_rx) = mpsc::channel::<StatusMessage>();
+
+    let join_handle = thread::spawn(move || threadloop(rx, opts, startup_tx, status_tx));
+
+    match startup_rx.recv() {
+        Ok(Ok(())) => Ok(AudioThreadHandle {
+            join_handle,
+            status_rx,
+        }),
+        Ok(Err(e)) => {
+            let _ = join_handle.join();
+            Err(e.into())
+        }
+        Err(_) => {
+            let _ = join_handle.join();
+            Err(ChannelDisconnectedError.into())
+        }
+    }
+}
 
-    pa_context.set_state_callback(Some(Box::new(context_state_changed)));
+fn threadloop(
+    rx: mpsc::Receiver<Message>,
+    opts: Option<Opts>,
+    startup_tx: SyncSender<Result<(), BackendError>>,
+    status_tx: Sender<StatusMessage>,
+) {
+    let mut opts = opts.unwrap_or_default();
 
-    pa_context
-        .connect(None, PulseContextFlagSet::NOAUTOSPAWN, None)
-        .expect("We should be able to connect to PulseAudio");
+    let conversion_quality = opts.conversion_quality;
+    let output_spec = opts.spec;
 
     log::log!(
         log::Level::Info,
-        "Connected to server {:?}",
-        pa_context.get_server()
+        "Audiothread starting up ({output_spec:?}, {conversion_quality:?})"
     );
 
-    let context_ready_timer = std::time::Instant::now();
-    let context_ready_timeout = std::time::Duration::from_secs(5);
-
-    while pa_context.get_state() != libpulse_binding::context::State::Ready {
-        match pa_mainloop.iterate(true) {
-            libpulse_binding::mainloop::standard::IterateResult::Success(_) => (),
-            libpulse_binding::mainloop::standard::IterateResult::Quit(_) => {
-                panic!("PulseAudio quit while audiothread was connecting")
-            }
-            libpulse_binding::mainloop::standard::IterateResult::Err(e) => {
-                panic!("PulseAudio error while audiothread was connecting: {e}")
-            }
-        }
-
-        if context_ready_timer.elapsed() > context_ready_timeout {
-            panic!("Timed out waiting for context to become ready");
-        }
-    }
+    let backend_setup = std::mem::replace(&mut opts.backend, BackendSetup::PulseAudio);
 
-    let mut stream =
-        PulseStream::new(&mut pa_context, "My Stream", &pulse_spec, None).expect("stream");
+    let mut backend = match make_backend(backend_setup, &opts) {
+        Ok(backend) if backend.spec() == output_spec => backend,
+        Ok(mut backend) => {
+            backend.shutdown();
 
-    let stream_raw: *mut PulseStream = &mut stream;
-    let stream_ssc = stream_raw;
-    let stream_srw = stream_raw;
+            let _ = startup_tx.send(Err(BackendError(format!(
+                "Backend spec {:?} does not match requested spec {output_spec:?}",
+                backend.spec()
+            ))));
 
-    let stream_state_changed = move || {
-        log::log!(log::Level::Debug, "Stream state changed: {:?}", unsafe {
-            (*stream_ssc).get_state()
-        },);
+            return;
+        }
+        Err(e) => {
+            log::log!(log::Level::Error, "Failed to start audio backend: {e}");
+            let _ = startup_tx.send(Err(e));
+            return;
+        }
     };
 
-    stream.set_state_callback(Some(Box::new(stream_state_changed)));
-
-    let stream_ready_write = move |n: usize| {
-        debug_assert!(n % framesize_bytes == 0);
-
-        // TODO: skip if no sources are playing.
-        //       complication: the buffer received from .begin_write may need to
-        //       to be zeroed once after the last playing source is dropped.
-        unsafe {
-            match (*stream_srw).begin_write(Some(n)) {
-                Ok(Some(ref mut buf)) => {
-                    let (prefix, buf_f32, suffix) = buf.align_to_mut::<f32>();
-
-                    debug_assert!(prefix.is_empty());
-                    debug_assert!(suffix.is_empty());
-
-                    buf_f32.fill(0.0);
-
-                    for (spec, group) in sourcegroups_srw.borrow_mut().iter_mut() {
-                        if *spec == output_spec {
-                            for source in group.sources_iter_mut() {
-                                source.mix_to_same_spec(buf_f32);
-                            }
-                        } else {
-                            group.mix_to_given_spec(output_spec, buf_f32);
-                        }
-                    }
-
-                    if let Err(e) = (*stream_srw).write(buf, None, 0, SeekMode::Relative) {
-                        log::log!(log::Level::Warn, "Error writing to stream: {:?}", e);
-                    }
-                }
+    let _ = startup_tx.send(Ok(()));
 
-                Ok(None) => log::log!(
-                    log::Level::Error,
-                    "Stream ready for writing, but .begin_write failed to provide a buffer"
-                ),
+    // messages are forwarded so that their arrival can wake the backend's wait
+    let (message_tx, message_rx) = mpsc::channel::<Message>();
+    let waker = backend.waker();
 
-                Err(e) => log::log!(
-                    log::Level::Error,
-                    "Stream ready for writing, but .begin_write failed with error {:?}",
-                    e
-                ),
-            }
-        }
-    };
+    thread::spawn(move || forward_messages(rx, message_tx, waker));
 
-    stream.set_write_callback(Some(Box::new(stream_ready_write)));
-
-    stream
-        .connect_playback(
-            None,
-            Some(&PulseBufferAttr {
-                maxlength: (opts.buffer_size.get() * framesize_bytes) as u32,
-                tlength: (opts.buffer_size.get() * framesize_bytes) as u32,
-                prebuf: 0,
-                minreq: (opts.buffer_size.get() * framesize_bytes) as u32,
-                fragsize: 0,
-            }),
-            PulseStreamFlagSet::ADJUST_LATENCY,
-            None,
-            None,
-        )
-        .expect("PulseAudio should let us connect our stream for playback");
-
-    let stream_ready_timer = std::time::Instant::now();
-    let stream_ready_timeout = std::time::Duration::from_secs(5);
-
-    while stream.get_state() != libpulse_binding::stream::State::Ready {
-        match pa_mainloop.iterate(true) {
-            libpulse_binding::mainloop::standard::IterateResult::Success(_) => (),
-            libpulse_binding::mainloop::standard::IterateResult::Quit(_) => {
-                panic!("PulseAudio quit while audiothread was creating a stream")
-            }
-            libpulse_binding::mainloop::standard::IterateResult::Err(e) => {
-                panic!("PulseAudio error while audiothread was creating a stream: {e}")
-            }
-        }
+    let mut mixer = Mixer::new(
+        output_spec,
+        conversion_quality,
+        opts.master_volume,
+        opts.limiter,
+        opts.voice_limit,
+    );
 
-        if stream_ready_timer.elapsed() > stream_ready_timeout {
-            panic!("Timed out waiting for stream to become ready");
-        }
-    }
+    let buffer_period = Duration::from_secs_f64(
+        opts.buffer_size.get() as f64 / output_spec.samplerate.get() as f64,
+    );
 
+    let mut metering: Option<ActiveSubscription<MeterReading>> = None;
+    let mut source_info_updates: Option<ActiveSubscription<HashMap<SourceId, SourceInfo>>> = None;
     let mut since_cleanup = Instant::now();
     let mut n_sources_playing_prev = 0;
+    let mut underruns_prev = 0;
+    let mut recorder: Option<Recorder> = None;
     let mut quit = false;
 
     loop {
-        match pa_mainloop.iterate(false) {
-            libpulse_binding::mainloop::standard::IterateResult::Success(_) => (),
-            libpulse_binding::mainloop::standard::IterateResult::Quit(_) => {
-                log::log!(log::Level::Error, "PulseAudio quit, shutting down");
-                break;
+        if let Err(e) = backend.process(&mut |buffer| mixer.render(buffer)) {
+            log::log!(log::Level::Error, "{e}, shutting down");
+            let _ = status_tx.send(StatusMessage::BackendFailed(e.to_string()));
+            break;
+        }
+
+        mixer.set_output_latency(backend.latency());
+
+        if let Some(subscription) = &mut metering {
+            if !subscription.publish_if_due(|| mixer.take_meter_reading()) {
+                log::log!(log::Level::Debug, "Meter receiver went away");
+                metering = None;
             }
-            libpulse_binding::mainloop::standard::IterateResult::Err(e) => {
-                log::log!(log::Level::Error, "PulseAudio error: {e}, shutting down");
-                break;
+        }
+
+        if let Some(subscription) = &mut source_info_updates {
+            if !subscription.publish_if_due(|| Some(mixer.all_source_info())) {
+                log::log!(log::Level::Debug, "Source info receiver went away");
+                source_info_updates = None;
             }
         }
 
-        // FIXME: what if sleeping here causes underflow on the sound server?
-        //        simple way to cause the issue is to play a very large number of
-        //        sources simultaneously. maybe the timeout value here should be a
-        //        parameter, or adjusted based on load
-        match recv_all(&rx, Duration::from_millis(2)) {
+        match recv_all(&message_rx, Duration::ZERO) {
             Ok(Some(messages)) => {
                 for message in messages {
                     match message {
@@ -339,32 +511,123 @@ fn threadloop(rx: mpsc::Receiver<Message>, opts: Option<Opts>) {
                             quit = true;
                             break;
                         }
-                        Message::DropAll => sourcegroups.borrow_mut().clear(),
-                        Message::DropAllMatching(matcher) => sourcegroups
-                            .borrow_mut()
-                            .iter_mut()
-                            .for_each(|(_, group)| group.drop_matching_sources(&matcher)),
-                        Message::PlaySymphoniaSource(sf) => {
-                            let _ = sourcegroups
-                                .borrow_mut()
-                                .entry(sf.spec())
-                                .or_insert(SourceGroup::new(
-                                    sf.spec(),
-                                    output_spec,
-                                    conversion_quality,
-                                ))
-                                .add_source(Source::SymphoniaSource(sf));
+                        Message::DropAll(fade_out) => mixer.drop_all(fade_out),
+                        Message::DropAllMatching(matcher, fade_out) => {
+                            mixer.drop_matching(&matcher, fade_out)
+                        }
+                        Message::PlaySymphoniaSource(id, sf, play_opts) => {
+                            mixer.add_source(id, Source::SymphoniaSource(sf), play_opts)
                         }
-                        Message::CreatePulledSource(setup) => {
-                            let _ = sourcegroups
-                                .borrow_mut()
-                                .entry(setup.spec)
-                                .or_insert(SourceGroup::new(
-                                    setup.spec,
-                                    output_spec,
-                                    conversion_quality,
-                                ))
-                                .add_source(Source::PulledSource(PulledSource::from_setup(setup)));
+                        Message::PlayBufferSource(id, source, play_opts) => {
+                            mixer.add_source(id, Source::BufferSource(source), play_opts)
+                        }
+                        Message::CreatePulledSource(id, setup, play_opts) => mixer.add_source(
+                            id,
+                            Source::PulledSource(PulledSource::from_setup(setup)),
+                            play_opts,
+                        ),
+                        Message::PlayQueueSource(id, source, play_opts) => {
+                            mixer.add_source(id, Source::QueueSource(source), play_opts)
+                        }
+                        Message::AppendToQueue {
+                            queue,
+                            item,
+                            source,
+                        } => match mixer.queue_mut(queue) {
+                            Some(queue_source) => {
+                                if let Err(e) = queue_source.append(item, source) {
+                                    log::log!(
+                                        log::Level::Error,
+                                        "Unable to append to queue {queue:?}: {e}"
+                                    );
+                                }
+                            }
+                            None => log::log!(log::Level::Warn, "Queue {queue:?} not found"),
+                        },
+                        Message::ClearQueue(id) => {
+                            if let Some(queue_source) = mixer.queue_mut(id) {
+                                queue_source.clear();
+                            }
+                        }
+                        Message::StopSource(id, fade_out) => mixer.drop_source(id, fade_out),
+                        Message::Pause(Some(matcher)) => mixer.set_paused_matching(&matcher, true),
+                        Message::Resume(Some(matcher)) => {
+                            mixer.set_paused_matching(&matcher, false)
+                        }
+                        Message::Pause(None) | Message::Resume(None) => {
+                            let paused = matches!(message, Message::Pause(_));
+
+                            if paused != mixer.is_paused() {
+                                mixer.set_paused(paused);
+
+                                if let Err(e) = backend.set_paused(paused) {
+                                    log::log!(
+                                        log::Level::Error,
+                                        "Failed to {} output: {e}",
+                                        if paused { "pause" } else { "resume" }
+                                    );
+                                }
+                            }
+                        }
+                        Message::PauseSource(id) => {
+                            if let Some(voice) = mixer.voice_mut(id) {
+                                voice.set_paused(true);
+                            }
+                        }
+                        Message::ResumeSource(id) => {
+                            if let Some(voice) = mixer.voice_mut(id) {
+                                voice.set_paused(false);
+                            }
+                        }
+                        Message::SetSourceGain(id, gain) => {
+                            if let Some(voice) = mixer.voice_mut(id) {
+                                voice.set_gain(gain);
+                            }
+                        }
+                        Message::SetSourceRate(id, rate) => {
+                            if let Some(voice) = mixer.voice_mut(id) {
+                                voice.set_rate(rate);
+                            }
+                        }
+                        Message::SetSourcePan(id, pan) => {
+                            if let Some(voice) = mixer.voice_mut(id) {
+                                voice.set_pan(pan);
+                            }
+                        }
+                        Message::SetMasterVolume(volume) => mixer.set_master_volume(volume),
+                        Message::SetBusGain(bus, gain) => mixer.bus_mut(&bus).set_gain(gain),
+                        Message::SetBusMute(bus, muted) => mixer.bus_mut(&bus).set_muted(muted),
+                        Message::SetBusSolo(bus, soloed) => mixer.bus_mut(&bus).set_soloed(soloed),
+                        Message::InsertEffect(bus, id, effect) => {
+                            mixer.insert_effect(bus.as_deref(), id, effect)
+                        }
+                        Message::RemoveEffect(id) => mixer.remove_effect(id),
+                        Message::SetEffectBypass(id, bypassed) => {
+                            mixer.set_effect_bypassed(id, bypassed)
+                        }
+                        Message::GetGainReduction(reply_tx) => {
+                            if let Err(e) = reply_tx.send(mixer.take_gain_reduction_db()) {
+                                log::log!(
+                                    log::Level::Error,
+                                    "Failed to provide gain reduction: {e}"
+                                );
+                            }
+                        }
+                        Message::SetMetering(setup) => {
+                            // discard levels accumulated while not metering
+                            let _ = mixer.take_meter_reading();
+                            metering =
+                                setup.map(|setup| ActiveSubscription::new(setup, buffer_period));
+                        }
+                        Message::GetSourceInfo(id, reply_tx) => {
+                            if let Err(e) = reply_tx.send(mixer.source_info(id)) {
+                                log::log!(log::Level::Error, "Failed to provide source info: {e}");
+                            }
+                        }
+                        Message::SetSourceInfoSubscription(subscription) => {
+                            source_info_updates = subscription.map(|subscription| {
+                                ActiveSubscription::new(subscription, buffer_period)
+                            });
                         }
                         Message::GetOutputSpec(reply_tx) => match reply_tx.send(output_spec) {
                             Ok(_) => (),
@@ -372,6 +635,32 @@ fn threadloop(rx: mpsc::Receiver<Message>, opts: Option<Opts>) {
                                 log::log!(log::Level::Error, "Failed to provide output spec: {e}");
                             }
                         },
+                        Message::AddTap(setup) => mixer.add_tap(setup),
+                        Message::StartRecording { path, format } => {
+                            let (new_recorder, tap_setup) =
+                                Recorder::start(path, format, output_spec, status_tx.clone());
+
+                            mixer.add_tap(tap_setup);
+
+                            if let Some(previous) = recorder.replace(new_recorder) {
+                                let _ = previous.stop();
+                            }
+                        }
+                        Message::StopRecording => {
+                            if let Some(recorder) = recorder.take() {
+                                let _ = recorder.stop();
+                            }
+                        }
+                        Message::GetStreamTime(reply_tx) => {
+                            if let Err(e) = reply_tx.send(mixer.stream_time()) {
+                                log::log!(log::Level::Error, "Failed to provide stream time: {e}");
+                            }
+                        }
+                        Message::GetUnderruns(reply_tx) => {
+                            if let Err(e) = reply_tx.send(backend.underruns()) {
+                                log::log!(log::Level::Error, "Failed to provide underruns: {e}");
+                            }
+                        }
                     }
                 }
             }
@@ -390,47 +679,67 @@ fn threadloop(rx: mpsc::Receiver<Message>, opts: Option<Opts>) {
             break;
         }
 
-        sourcegroups
-            .borrow_mut()
-            .iter_mut()
-            .flat_map(|(_spec, group)| group.sources_iter_mut())
-            .filter_map(|source| match source {
-                Source::PulledSource(ps) => Some(ps),
-                _ => None,
-            })
-            .for_each(|source| source.update());
+        mixer.update_pulled_sources();
+        mixer.drain_finished_queue_items(|queue, item| {
+            let _ = status_tx.send(StatusMessage::QueueItemFinished { queue, item });
+        });
+
+        for id in mixer.take_rejected_sources() {
+            let _ = status_tx.send(StatusMessage::SourceRejected(id));
+        }
 
         if since_cleanup.elapsed().as_millis() >= 1000 {
             since_cleanup = Instant::now();
 
-            for (_spec, group) in sourcegroups.borrow_mut().iter_mut() {
-                group.drop_completed_sources();
-            }
+            mixer.drop_completed();
 
-            let n_sources_playing = sourcegroups
-                .borrow()
-                .iter()
-                .map(|(_spec, group)| group.sources_len())
-                .sum::<usize>();
+            let n_sources_playing = mixer.sources_len();
 
             if n_sources_playing != n_sources_playing_prev {
                 log::log!(log::Level::Debug, "{} sources playing", n_sources_playing);
 
                 n_sources_playing_prev = n_sources_playing;
             }
+
+            let underruns = backend.underruns();
+
+            if underruns != underruns_prev {
+                log::log!(log::Level::Debug, "{} underruns", underruns);
+
+                underruns_prev = underruns;
+            }
+        }
+
+        // sleep until the backend needs audio, a message arrives or periodic work is due
+        let next_wakeup = [
+            Some(since_cleanup + Duration::from_secs(1)),
+            metering.as_ref().map(ActiveSubscription::next_due),
+            source_info_updates
+                .as_ref()
+                .map(ActiveSubscription::next_due),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or_else(Instant::now);
+
+        if let Err(e) = backend.wait(next_wakeup.saturating_duration_since(Instant::now())) {
+            log::log!(log::Level::Error, "{e}, shutting down");
+            let _ = status_tx.send(StatusMessage::BackendFailed(e.to_string()));
+            break;
         }
     }
 
     log::log!(log::Level::Info, "Audiothread shutting down gracefully");
 
-    stream
-        .disconnect()
-        .expect("We should be able to disconnect from PulseAudio");
+    // the file is complete by the time the shutdown is reported
+    if let Some(recorder) = recorder.take() {
+        let _ = recorder.stop().join();
+    }
 
-    pa_context.disconnect();
+    backend.shutdown();
 
-    // is this needed/beneficial?
-    pa_mainloop.quit(PulseRetval(0));
+    let _ = status_tx.send(StatusMessage::ShutDown);
 }
 
 #[cfg(test)]
@@ -447,11 +756,17 @@ mod tests {
         let opts = opts.with_spec(AudioSpec::new(22500, 2).unwrap());
         let opts = opts.with_conversion_quality(Quality::Medium);
         let opts = opts.with_buffer_size(NonZeroNumFrames::new(31415).unwrap());
+        let opts = opts.with_master_volume(0.5);
+        let opts = opts.with_limiter(Limiter::SoftClip { ceiling: 1.0 });
+        let opts = opts.with_voice_limit(0, VoiceStealing::Quietest);
 
         assert_eq!(opts.stream_name, "Sound Effects");
         assert_eq!(opts.spec.samplerate, Samplerate::new(22500).unwrap());
         assert_eq!(opts.conversion_quality, Quality::Medium);
         assert_eq!(opts.buffer_size, NonZeroNumFrames::new(31415).unwrap());
+        assert_eq!(opts.master_volume, 0.5);
+        assert_eq!(opts.limiter, Limiter::SoftClip { ceiling: 1.0 });
+        assert_eq!(opts.voice_limit, Some((1, VoiceStealing::Quietest)));
 
         let opts = Opts::default()
             .with_name("Background Music")
@@ -466,7 +781,7 @@ mod tests {
         fn fmt_message(x: Message) -> String {
             match x {
                 Message::Shutdown => String::from("Shutdown"),
-                Message::DropAll => String::from("DropAll"),
+                Message::DropAll(_) => String::from("DropAll"),
                 _ => String::from(""),
             }
         }
@@ -475,9 +790,9 @@ mod tests {
 
         assert!(recv_all(&rx, Duration::from_millis(0)).is_ok_and(|m| m.is_none()));
 
-        tx.send(Message::DropAll).unwrap();
+        tx.send(Message::DropAll(None)).unwrap();
         tx.send(Message::Shutdown).unwrap();
-        tx.send(Message::DropAll).unwrap();
+        tx.send(Message::DropAll(None)).unwrap();
 
         let x = recv_all(&rx, Duration::from_millis(0))
             .unwrap()
@@ -0,0 +1,181 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::time::Duration;
+
+use crate::types::AudioSpec;
+
+/// Lowest ceiling accepted, about -80 dBFS.
+const MIN_CEILING: f32 = 1e-4;
+
+/// Fraction of the ceiling below which the soft clipper leaves samples untouched.
+const SOFT_CLIP_KNEE: f32 = 0.5;
+
+/// Master-stage limiting applied after all sources have been mixed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Limiter {
+    #[default]
+    Off,
+
+    /// Smoothly saturate samples towards `ceiling` (linear, typically 1.0), leaving
+    /// samples below half the ceiling untouched.
+    SoftClip { ceiling: f32 },
+
+    /// Keep peaks at or below `ceiling` (linear) by instantly reducing gain, letting it
+    /// recover over `release`.
+    Peak { ceiling: f32, release: Duration },
+}
+
+impl Limiter {
+    /// The limiter with its ceiling raised to a small positive minimum if below it.
+    pub(crate) fn clamped(self) -> Self {
+        match self {
+            Limiter::Off => Limiter::Off,
+            Limiter::SoftClip { ceiling } => Limiter::SoftClip {
+                ceiling: ceiling.max(MIN_CEILING),
+            },
+            Limiter::Peak { ceiling, release } => Limiter::Peak {
+                ceiling: ceiling.max(MIN_CEILING),
+                release,
+            },
+        }
+    }
+}
+
+fn soft_clip(input: f32, ceiling: f32) -> f32 {
+    let knee = ceiling * SOFT_CLIP_KNEE;
+    let magnitude = input.abs();
+
+    if magnitude <= knee {
+        input
+    } else {
+        let range = ceiling - knee;
+        (knee + range * ((magnitude - knee) / range).tanh()).copysign(input)
+    }
+}
+
+/// Running state of the master limiter.
+pub(crate) struct LimiterState {
+    limiter: Limiter,
+    channels: usize,
+    gain: f32,
+    release_coeff: f32,
+    max_gain_reduction: f32,
+}
+
+impl LimiterState {
+    pub fn new(limiter: Limiter, spec: AudioSpec) -> Self {
+        let release_coeff = match limiter {
+            Limiter::Peak { release, .. } if !release.is_zero() => {
+                (-1.0 / (release.as_secs_f32() * spec.samplerate.get() as f32)).exp()
+            }
+            _ => 0.0,
+        };
+
+        Self {
+            limiter: limiter.clamped(),
+            channels: spec.channels.get() as usize,
+            gain: 1.0,
+            release_coeff,
+            max_gain_reduction: 1.0,
+        }
+    }
+
+    /// Apply the limiter to `buffer` in place.
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        match self.limiter {
+            Limiter::Off => (),
+
+            Limiter::SoftClip { ceiling } => {
+                for sample in buffer.iter_mut() {
+                    let input = *sample;
+                    *sample = soft_clip(input, ceiling);
+
+                    if *sample != input {
+                        self.max_gain_reduction = self.max_gain_reduction.min(*sample / input);
+                    }
+                }
+            }
+
+            Limiter::Peak { ceiling, .. } => {
+                for frame in buffer.chunks_exact_mut(self.channels) {
+                    let peak = frame.iter().fold(0.0f32, |acc, x| acc.max(x.abs()));
+                    let target = if peak > ceiling { ceiling / peak } else { 1.0 };
+
+                    self.gain = if target < self.gain {
+                        target
+                    } else {
+                        target + (self.gain - target) * self.release_coeff
+                    };
+
+                    frame.iter_mut().for_each(|sample| *sample *= self.gain);
+                    self.max_gain_reduction = self.max_gain_reduction.min(self.gain);
+                }
+            }
+        }
+    }
+
+    /// Largest gain reduction applied since the last call, in dB (0.0 or greater).
+    pub fn take_gain_reduction_db(&mut self) -> f32 {
+        let gain = std::mem::replace(&mut self.max_gain_reduction, 1.0);
+
+        if gain >= 1.0 {
+            0.0
+        } else if gain > 0.0 {
+            -20.0 * gain.log10()
+        } else {
+            f32::INFINITY
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peak_limiter() {
+        let spec = AudioSpec::new(1000, 1).unwrap();
+        let mut state = LimiterState::new(
+            Limiter::Peak {
+                ceiling: 1.0,
+                release: Duration::from_millis(10),
+            },
+            spec,
+        );
+
+        let mut buffer = vec![0.5, 2.0, 0.5, 0.5];
+        state.process(&mut buffer);
+
+        assert_eq!(buffer[0], 0.5);
+        assert_eq!(buffer[1], 1.0);
+        assert!(buffer[2] < 0.5 && buffer[3] > buffer[2]);
+        assert!((state.take_gain_reduction_db() - 6.0206).abs() < 1e-3);
+        assert_eq!(state.take_gain_reduction_db(), 0.0);
+    }
+
+    #[test]
+    fn test_soft_clip() {
+        let spec = AudioSpec::new(1000, 2).unwrap();
+        let mut state = LimiterState::new(Limiter::SoftClip { ceiling: 1.0 }, spec);
+
+        let mut buffer = vec![0.0, 0.1, 0.5, 0.6, 4.0, -4.0];
+        state.process(&mut buffer);
+
+        // untouched below the knee
+        assert_eq!(buffer[..3], [0.0, 0.1, 0.5]);
+        assert!(buffer[3] > 0.59 && buffer[3] < 0.6);
+        assert!(buffer[4] > 0.99 && buffer[4] <= 1.0);
+        assert!(buffer[5] < -0.99 && buffer[5] >= -1.0);
+        assert!(state.take_gain_reduction_db() > 11.0);
+
+        let mut state = LimiterState::new(Limiter::SoftClip { ceiling: 0.0 }, spec);
+        let mut buffer = vec![0.5, -0.5];
+        state.process(&mut buffer);
+
+        assert!(buffer
+            .iter()
+            .all(|x| x.is_finite() && x.abs() <= MIN_CEILING));
+    }
+}
@@ -0,0 +1,85 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::collections::HashMap;
+
+use crate::{subscription::Subscription, types::SourceId};
+
+/// Peak and RMS levels (linear) over a metering interval.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Levels {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+/// Levels measured by the audio thread over one metering interval.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MeterReading {
+    /// Levels of the final mix, one per output channel.
+    pub master: Vec<Levels>,
+
+    /// Levels of each playing source after gain and pan, over all output channels.
+    pub sources: HashMap<SourceId, Levels>,
+}
+
+/// Setup for receiving [`MeterReading`]s from the audio thread.
+pub type MeteringSetup = Subscription<MeterReading>;
+
+/// Accumulates peak and RMS levels of a stream of samples.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LevelMeter {
+    peak: f32,
+    sum_squares: f64,
+    num_samples: usize,
+}
+
+impl LevelMeter {
+    pub fn add(&mut self, sample: f32) {
+        self.peak = self.peak.max(sample.abs());
+        self.sum_squares += (sample as f64) * (sample as f64);
+        self.num_samples += 1;
+    }
+
+    pub fn num_samples(&self) -> usize {
+        self.num_samples
+    }
+
+    /// Levels since the previous call, resetting the meter.
+    pub fn take(&mut self) -> Levels {
+        let levels = Levels {
+            peak: self.peak,
+            rms: if self.num_samples > 0 {
+                (self.sum_squares / self.num_samples as f64).sqrt() as f32
+            } else {
+                0.0
+            },
+        };
+
+        *self = Self::default();
+
+        levels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_meter() {
+        let mut meter = LevelMeter::default();
+
+        assert_eq!(meter.take(), Levels::default());
+
+        [0.5, -1.0, 0.5, -1.0]
+            .into_iter()
+            .for_each(|sample| meter.add(sample));
+
+        let levels = meter.take();
+
+        assert_eq!(levels.peak, 1.0);
+        assert!((levels.rms - 0.625f32.sqrt()).abs() < 1e-6);
+        assert_eq!(meter.num_samples(), 0);
+    }
+}
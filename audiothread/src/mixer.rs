@@ -0,0 +1,540 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    bus::{apply_gain_ramp, Bus},
+    effect::{Effect, EffectChain},
+    limiter::{Limiter, LimiterState},
+    meter::{LevelMeter, MeterReading},
+    source::{
+        queue::QueueSource,
+        voice::{PlayOpts, SourceInfo, Voice},
+        Source, SourceGroup, SourceMatcher,
+    },
+    tap::{Tap, TapSetup},
+    types::{AudioSpec, EffectId, FadeLength, NumFrames, Quality, SourceId, VoiceStealing},
+};
+
+/// Fade-out of sources stopped to make way for new sources.
+const STOLEN_VOICE_FADE: FadeLength = FadeLength::Duration(Duration::from_millis(5));
+
+/// Mixes all playing sources into buffers of the output spec.
+pub(crate) struct Mixer {
+    output_spec: AudioSpec,
+    conversion_quality: Quality,
+    buses: HashMap<String, Bus>,
+    master_effects: EffectChain,
+    master_volume: f32,
+    applied_master_volume: f32,
+    limiter: LimiterState,
+    voice_limit: Option<(usize, VoiceStealing)>,
+    master_meters: Vec<LevelMeter>,
+    output_latency: Option<Duration>,
+    paused: bool,
+    stream_time: NumFrames,
+    taps: Vec<Tap>,
+
+    /// Sources not started because of the voice limit, yet to be reported.
+    rejected: Vec<SourceId>,
+}
+
+impl Mixer {
+    pub fn new(
+        output_spec: AudioSpec,
+        conversion_quality: Quality,
+        master_volume: f32,
+        limiter: Limiter,
+        voice_limit: Option<(usize, VoiceStealing)>,
+    ) -> Self {
+        Self {
+            output_spec,
+            conversion_quality,
+            buses: HashMap::new(),
+            master_effects: EffectChain::default(),
+            master_volume,
+            applied_master_volume: master_volume,
+            limiter: LimiterState::new(limiter, output_spec),
+            voice_limit,
+            master_meters: vec![LevelMeter::default(); output_spec.channels.get() as usize],
+            output_latency: None,
+            paused: false,
+            stream_time: NumFrames::new(0),
+            taps: Vec::new(),
+            rejected: Vec::new(),
+        }
+    }
+
+    fn groups(&self) -> impl Iterator<Item = &SourceGroup> {
+        self.buses.values().flat_map(|bus| bus.groups())
+    }
+
+    fn groups_mut(&mut self) -> impl Iterator<Item = &mut SourceGroup> {
+        self.buses.values_mut().flat_map(|bus| bus.groups_mut())
+    }
+
+    /// The named bus, created if needed.
+    pub fn bus_mut(&mut self, name: &str) -> &mut Bus {
+        if !self.buses.contains_key(name) {
+            self.buses
+                .insert(name.to_string(), Bus::new(self.output_spec));
+        }
+
+        self.buses.get_mut(name).expect("Bus should exist")
+    }
+
+    /// Insert an effect last in the chain of the named bus, created if needed, or of the
+    /// master output if `None`.
+    pub fn insert_effect(&mut self, bus: Option<&str>, id: EffectId, effect: Box<dyn Effect>) {
+        let output_spec = self.output_spec;
+
+        match bus {
+            Some(name) => self.bus_mut(name).effects_mut(),
+            None => &mut self.master_effects,
+        }
+        .insert(id, effect, output_spec);
+    }
+
+    fn effect_chains_mut(&mut self) -> impl Iterator<Item = &mut EffectChain> {
+        std::iter::once(&mut self.master_effects)
+            .chain(self.buses.values_mut().map(|bus| bus.effects_mut()))
+    }
+
+    pub fn remove_effect(&mut self, id: EffectId) {
+        if !self.effect_chains_mut().any(|chain| chain.remove(id)) {
+            log::log!(log::Level::Debug, "Effect {id:?} not found for removal");
+        }
+    }
+
+    pub fn set_effect_bypassed(&mut self, id: EffectId, bypassed: bool) {
+        if !self
+            .effect_chains_mut()
+            .any(|chain| chain.set_bypassed(id, bypassed))
+        {
+            log::log!(log::Level::Debug, "Effect {id:?} not found for bypass");
+        }
+    }
+
+    /// Number of output frames rendered so far, not counting any rendered while paused.
+    pub fn stream_time(&self) -> NumFrames {
+        self.stream_time
+    }
+
+    /// Set the latency reported by the output backend, used for audible positions.
+    pub fn set_output_latency(&mut self, latency: Option<Duration>) {
+        self.output_latency = latency;
+    }
+
+    /// Pause or resume the whole mix. While paused nothing is mixed and sources keep
+    /// their positions.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pause or resume the sources matched by `matcher`.
+    pub fn set_paused_matching(&mut self, matcher: &SourceMatcher, paused: bool) {
+        self.groups_mut()
+            .flat_map(|group| group.voices_iter_mut())
+            .filter(|voice| matcher.matches(voice))
+            .for_each(|voice| voice.set_paused(paused));
+    }
+
+    /// Fades would never progress while the mix is paused, so sources are then dropped
+    /// right away.
+    fn fade_unless_paused(&self, fade_out: Option<FadeLength>) -> Option<FadeLength> {
+        fade_out.filter(|_| !self.paused)
+    }
+
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.master_volume = volume.max(0.0);
+    }
+
+    /// Largest limiter gain reduction since the previous call, in dB.
+    pub fn take_gain_reduction_db(&mut self) -> f32 {
+        self.limiter.take_gain_reduction_db()
+    }
+
+    /// Levels since the previous call, or `None` if nothing has been rendered since.
+    pub fn take_meter_reading(&mut self) -> Option<MeterReading> {
+        if self.master_meters[0].num_samples() == 0 {
+            return None;
+        }
+
+        Some(MeterReading {
+            master: self.master_meters.iter_mut().map(|m| m.take()).collect(),
+            sources: self
+                .groups_mut()
+                .flat_map(|group| group.voices_iter_mut())
+                .map(|voice| (voice.id(), voice.take_levels()))
+                .collect(),
+        })
+    }
+
+    /// Make way for a new source if the voice limit has been reached, returning false if
+    /// the new source should not be started.
+    fn make_way_for_voice(&mut self) -> bool {
+        let Some((max_voices, stealing)) = self.voice_limit else {
+            return true;
+        };
+
+        let live_voices = self
+            .groups()
+            .flat_map(|group| group.voices_iter())
+            .filter(|voice| voice.is_live())
+            .count();
+
+        if live_voices < max_voices {
+            return true;
+        }
+
+        let voices = self
+            .groups_mut()
+            .flat_map(|group| group.voices_iter_mut())
+            .filter(|voice| voice.is_live());
+
+        let stolen = match stealing {
+            VoiceStealing::Oldest => voices.min_by_key(|voice| (voice.play_time(), voice.id())),
+            VoiceStealing::Quietest => {
+                // voices yet to be heard are only stolen if no other voice can be
+                let loudness = |voice: &Voice| voice.loudness().unwrap_or(f32::INFINITY);
+                voices.min_by(|a, b| loudness(a).total_cmp(&loudness(b)))
+            }
+            VoiceStealing::RejectNew => {
+                log::log!(
+                    log::Level::Debug,
+                    "Voice limit reached, not starting source"
+                );
+                return false;
+            }
+        };
+
+        if let Some(voice) = stolen {
+            log::log!(
+                log::Level::Debug,
+                "Voice limit reached, stopping {:?}",
+                voice.id()
+            );
+
+            let frames = STOLEN_VOICE_FADE.frames(voice.spec().samplerate);
+            voice.fade_out(frames);
+        }
+
+        true
+    }
+
+    pub fn add_source(&mut self, id: SourceId, source: Source, opts: PlayOpts) {
+        if !self.make_way_for_voice() {
+            self.rejected.push(id);
+            return;
+        }
+
+        let voice = Voice::new(
+            id,
+            source,
+            opts,
+            self.output_spec.channels,
+            self.conversion_quality,
+            self.stream_time,
+        );
+        let (output_spec, conversion_quality) = (self.output_spec, self.conversion_quality);
+
+        let _ = self
+            .bus_mut(voice.bus())
+            .add_voice(voice, output_spec, conversion_quality);
+    }
+
+    pub fn voice_mut(&mut self, id: SourceId) -> Option<&mut Voice> {
+        self.groups_mut()
+            .flat_map(|group| group.voices_iter_mut())
+            .find(|voice| voice.id() == id)
+    }
+
+    pub fn source_info(&self, id: SourceId) -> Option<SourceInfo> {
+        self.groups()
+            .flat_map(|group| group.voices_iter())
+            .find(|voice| voice.id() == id)
+            .map(|voice| voice.info(self.output_latency))
+    }
+
+    pub fn all_source_info(&self) -> HashMap<SourceId, SourceInfo> {
+        self.groups()
+            .flat_map(|group| group.voices_iter())
+            .map(|voice| (voice.id(), voice.info(self.output_latency)))
+            .collect()
+    }
+
+    pub fn drop_source(&mut self, id: SourceId, fade_out: Option<FadeLength>) {
+        let fade_out = self.fade_unless_paused(fade_out);
+
+        self.groups_mut()
+            .for_each(|group| group.drop_source(id, fade_out));
+    }
+
+    pub fn drop_all(&mut self, fade_out: Option<FadeLength>) {
+        match self.fade_unless_paused(fade_out) {
+            Some(_) => self
+                .groups_mut()
+                .for_each(|group| group.drop_all_sources(fade_out)),
+            None => self.buses.values_mut().for_each(Bus::clear),
+        }
+    }
+
+    pub fn drop_matching(&mut self, matcher: &SourceMatcher, fade_out: Option<FadeLength>) {
+        let fade_out = self.fade_unless_paused(fade_out);
+
+        self.groups_mut()
+            .for_each(|group| group.drop_matching_sources(matcher, fade_out));
+    }
+
+    /// Copy the final mix into the given tap from now on.
+    pub fn add_tap(&mut self, setup: TapSetup) {
+        self.taps.push(Tap::new(setup, self.output_spec));
+    }
+
+    /// Drop completed sources, and taps whose receiver has gone away.
+    pub fn drop_completed(&mut self) {
+        self.groups_mut()
+            .for_each(|group| group.drop_completed_sources());
+
+        self.taps.retain(|tap| tap.is_connected());
+    }
+
+    pub fn sources_len(&self) -> usize {
+        self.groups().map(|group| group.sources_len()).sum()
+    }
+
+    pub fn update_pulled_sources(&mut self) {
+        let stream_time = self.stream_time;
+        let output_rate = self.output_spec.samplerate;
+
+        self.groups_mut()
+            .flat_map(|group| group.voices_iter_mut())
+            // a paused voice doesn't drain its buffer, and when it will be heard again
+            // is unknown, so its producer is left alone until it's resumed
+            .filter(|voice| !voice.is_paused())
+            .for_each(|voice| {
+                let play_time = voice
+                    .start_time()
+                    .map_or(stream_time, |time| time.max(stream_time));
+
+                if let Source::PulledSource(ps) = voice.source_mut() {
+                    ps.update(play_time, output_rate);
+                }
+            });
+    }
+
+    pub fn queue_mut(&mut self, id: SourceId) -> Option<&mut QueueSource> {
+        match self.voice_mut(id)?.source_mut() {
+            Source::QueueSource(queue) => Some(queue),
+            _ => None,
+        }
+    }
+
+    /// Ids of the sources not started because of the voice limit since the previous call.
+    pub fn take_rejected_sources(&mut self) -> impl Iterator<Item = SourceId> + '_ {
+        self.rejected.drain(..)
+    }
+
+    /// Call `f` with the id of each queue and of each of its items finished since the
+    /// previous call.
+    pub fn drain_finished_queue_items(&mut self, mut f: impl FnMut(SourceId, SourceId)) {
+        self.groups_mut()
+            .flat_map(|group| group.voices_iter_mut())
+            .for_each(|voice| {
+                let queue_id = voice.id();
+
+                if let Source::QueueSource(queue) = voice.source_mut() {
+                    queue
+                        .take_finished()
+                        .for_each(|item_id| f(queue_id, item_id));
+                }
+            });
+    }
+
+    /// Render the mix of all sources into `buffer`, overwriting its contents.
+    pub fn render(&mut self, buffer: &mut [f32]) {
+        debug_assert!(buffer
+            .len()
+            .is_multiple_of(self.output_spec.channels.get() as usize));
+
+        buffer.fill(0.0);
+
+        if self.paused {
+            return;
+        }
+
+        let any_soloed = self.buses.values().any(Bus::is_soloed);
+
+        for bus in self.buses.values_mut() {
+            bus.mix_to_given_spec(self.output_spec, buffer, self.stream_time, any_soloed);
+        }
+
+        let chans = self.output_spec.channels.get() as usize;
+
+        self.stream_time = NumFrames::new(self.stream_time.get() + buffer.len() / chans);
+
+        self.master_effects.process(self.output_spec, buffer);
+
+        apply_gain_ramp(
+            buffer,
+            chans,
+            self.applied_master_volume,
+            self.master_volume,
+        );
+        self.applied_master_volume = self.master_volume;
+
+        self.limiter.process(buffer);
+
+        for frame in buffer.chunks_exact(chans) {
+            frame
+                .iter()
+                .zip(self.master_meters.iter_mut())
+                .for_each(|(sample, meter)| meter.add(*sample));
+        }
+
+        self.taps.iter_mut().for_each(|tap| tap.write(buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        sync::Arc,
+    };
+
+    use super::*;
+    use crate::source::{
+        buffer::BufferSource,
+        queue::{QueueItem, QueueSource},
+    };
+
+    struct CountingAllocator;
+
+    thread_local! {
+        static COUNTING: Cell<bool> = const { Cell::new(false) };
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if COUNTING.with(Cell::get) {
+                ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            }
+
+            System.alloc(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            if COUNTING.with(Cell::get) {
+                ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            }
+
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Number of allocations made on this thread while running `f`.
+    fn count_allocations(f: impl FnOnce()) -> usize {
+        ALLOCATIONS.with(|count| count.set(0));
+        COUNTING.with(|counting| counting.set(true));
+        f();
+        COUNTING.with(|counting| counting.set(false));
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    fn sine(spec: AudioSpec, seconds: usize) -> Arc<[f32]> {
+        let chans = spec.channels.get() as usize;
+        let rate = spec.samplerate.get() as f32;
+
+        (0..spec.samplerate.get() as usize * seconds)
+            .flat_map(|n| std::iter::repeat_n((n as f32 * 440.0 / rate).sin() * 0.5, chans))
+            .collect()
+    }
+
+    /// Covers buffer and queue sources. Symphonia sources are left out, as symphonia
+    /// allocates each packet it reads from a file.
+    #[test]
+    fn test_render_does_not_allocate() {
+        let output_spec = AudioSpec::new(48000, 2).unwrap();
+        let mono = AudioSpec::new(44100, 1).unwrap();
+        let stereo = AudioSpec::new(48000, 2).unwrap();
+
+        let mut mixer = Mixer::new(
+            output_spec,
+            Quality::Medium,
+            0.8,
+            Limiter::Peak {
+                ceiling: 0.5,
+                release: Duration::from_millis(50),
+            },
+            None,
+        );
+
+        let mono_id = SourceId::unique();
+
+        mixer.add_source(
+            mono_id,
+            Source::BufferSource(BufferSource::new(mono, sine(mono, 2)).unwrap()),
+            PlayOpts::default()
+                .with_pan(-0.5)
+                .with_fade_in(FadeLength::Frames(NumFrames::new(1000))),
+        );
+
+        mixer.add_source(
+            SourceId::unique(),
+            Source::BufferSource(BufferSource::new(stereo, sine(stereo, 2)).unwrap()),
+            PlayOpts::default().with_gain(0.5),
+        );
+
+        // items finish and crossfade into the next while rendering
+        let mut queue =
+            QueueSource::new(mono).with_crossfade(FadeLength::Frames(NumFrames::new(100)));
+
+        for _ in 0..4 {
+            let item = BufferSource::new(mono, sine(mono, 1)[..3000].into()).unwrap();
+            queue
+                .append(SourceId::unique(), QueueItem::BufferSource(item))
+                .unwrap();
+        }
+
+        mixer.add_source(
+            SourceId::unique(),
+            Source::QueueSource(queue),
+            PlayOpts::default(),
+        );
+
+        let mut buffer = vec![0.0f32; 4096 * 2];
+
+        let allocations = count_allocations(|| {
+            for frames in [256, 1024, 4096, 17, 512, 2048] {
+                mixer.render(&mut buffer[..frames * 2]);
+            }
+
+            mixer.voice_mut(mono_id).unwrap().set_pan(0.5);
+            mixer.set_master_volume(1.0);
+            mixer.drop_source(mono_id, Some(FadeLength::Frames(NumFrames::new(500))));
+
+            for frames in [1024, 333, 4096] {
+                mixer.render(&mut buffer[..frames * 2]);
+            }
+        });
+
+        assert!(buffer.iter().any(|sample| *sample != 0.0));
+        assert_eq!(allocations, 0);
+    }
+}
@@ -0,0 +1,142 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use ringbuf::traits::Consumer;
+
+use crate::{
+    tap::{TapReceiver, TapSetup},
+    types::AudioSpec,
+    StatusMessage,
+};
+
+/// Sample format of recorded WAV files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecordingFormat {
+    #[default]
+    Float32,
+    Int16,
+    Int24,
+}
+
+impl RecordingFormat {
+    fn wav_spec(&self, spec: AudioSpec) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            RecordingFormat::Float32 => (32, hound::SampleFormat::Float),
+            RecordingFormat::Int16 => (16, hound::SampleFormat::Int),
+            RecordingFormat::Int24 => (24, hound::SampleFormat::Int),
+        };
+
+        hound::WavSpec {
+            channels: spec.channels.get() as u16,
+            sample_rate: spec.samplerate.get(),
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+/// A recording in progress, written to disk by a thread of its own. Dropping the
+/// recorder stops the recording and finalizes the file.
+pub(crate) struct Recorder {
+    stop_tx: Sender<()>,
+    join_handle: JoinHandle<()>,
+}
+
+impl Recorder {
+    /// Start recording the mix received through a tap, returning the recorder along
+    /// with the tap to add to the mixer.
+    pub fn start(
+        path: PathBuf,
+        format: RecordingFormat,
+        spec: AudioSpec,
+        status_tx: Sender<StatusMessage>,
+    ) -> (Self, TapSetup) {
+        let (stop_tx, stop_rx) = channel::<()>();
+
+        // two seconds leaves plenty of room for the writer falling behind
+        let (tap_setup, tap) =
+            TapSetup::new(2 * spec.samplerate.get() as usize * spec.channels.get() as usize);
+
+        let join_handle = thread::spawn(move || {
+            let result = record(&path, format, spec, tap, || {
+                matches!(stop_rx.try_recv(), Err(TryRecvError::Disconnected))
+            });
+
+            let status = match result {
+                Ok(()) => StatusMessage::RecordingFinished(path),
+                Err(e) => {
+                    log::log!(log::Level::Error, "Recording to {path:?} failed: {e}");
+                    StatusMessage::RecordingFailed(e.to_string())
+                }
+            };
+
+            let _ = status_tx.send(status);
+        });
+
+        (
+            Self {
+                stop_tx,
+                join_handle,
+            },
+            tap_setup,
+        )
+    }
+
+    /// Stop recording. The writer finishes the file in the background, joining the
+    /// returned handle waits for it to be done.
+    pub fn stop(self) -> JoinHandle<()> {
+        // the writer stops once the channel is disconnected
+        drop(self.stop_tx);
+        self.join_handle
+    }
+}
+
+fn record(
+    path: &Path,
+    format: RecordingFormat,
+    spec: AudioSpec,
+    mut tap: TapReceiver,
+    stopped: impl Fn() -> bool,
+) -> Result<(), hound::Error> {
+    let mut writer = hound::WavWriter::create(path, format.wav_spec(spec))?;
+    let mut done = false;
+
+    while !done {
+        // check before draining, so that everything mixed before stopping is written
+        done = stopped();
+
+        for sample in tap.buffer_rx().pop_iter() {
+            match format {
+                RecordingFormat::Float32 => writer.write_sample(sample)?,
+                RecordingFormat::Int16 => {
+                    writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?
+                }
+                RecordingFormat::Int24 => {
+                    writer.write_sample((sample.clamp(-1.0, 1.0) * 8388607.0) as i32)?
+                }
+            }
+        }
+
+        if !done {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    if tap.overruns() > 0 {
+        log::log!(
+            log::Level::Warn,
+            "Recording to {path:?} dropped audio {} times",
+            tap.overruns()
+        );
+    }
+
+    writer.finalize()
+}
@@ -0,0 +1,122 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::ffi::CStr;
+
+use libsamplerate_sys::{
+    src_delete, src_new, src_process, src_strerror, SRC_DATA, SRC_LINEAR, SRC_SINC_BEST_QUALITY,
+    SRC_SINC_FASTEST, SRC_SINC_MEDIUM_QUALITY, SRC_STATE,
+};
+
+use crate::{
+    error::ResamplerError,
+    types::{NumChannels, NumFrames, Quality, Samplerate},
+};
+
+/// Sample rate converter writing into caller-provided buffers.
+///
+/// Wraps libsamplerate directly since the `samplerate` crate allocates a new output
+/// buffer on every call.
+pub(crate) struct Resampler {
+    state: *mut SRC_STATE,
+    channels: usize,
+    ratio: f64,
+}
+
+// the converter state is only ever accessed through &mut self
+unsafe impl Send for Resampler {}
+
+impl std::fmt::Debug for Resampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "Resampler(channels: {}, ratio: {})",
+            self.channels, self.ratio
+        ))
+    }
+}
+
+fn error_message(code: i32) -> String {
+    let message = unsafe { src_strerror(code) };
+
+    if message.is_null() {
+        format!("libsamplerate error {code}")
+    } else {
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl Resampler {
+    pub fn new(
+        quality: Quality,
+        from_rate: Samplerate,
+        to_rate: Samplerate,
+        channels: NumChannels,
+    ) -> Result<Self, ResamplerError> {
+        let converter_type = match quality {
+            Quality::Lowest => SRC_LINEAR,
+            Quality::Low => SRC_SINC_FASTEST,
+            Quality::Medium => SRC_SINC_MEDIUM_QUALITY,
+            Quality::High => SRC_SINC_BEST_QUALITY,
+        };
+
+        let mut error = 0;
+        let state = unsafe { src_new(converter_type as i32, channels.get() as i32, &mut error) };
+
+        if state.is_null() {
+            return Err(ResamplerError(error_message(error)));
+        }
+
+        Ok(Self {
+            state,
+            channels: channels.get() as usize,
+            ratio: to_rate.get() as f64 / from_rate.get() as f64,
+        })
+    }
+
+    /// Output frames produced per input frame.
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// Change the ratio, which libsamplerate transitions to smoothly over the next call
+    /// to [`Resampler::process`].
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    /// Convert interleaved `input` into `output`, returning the number of input frames
+    /// consumed and the number of output frames written.
+    pub fn process(
+        &mut self,
+        input: &[f32],
+        output: &mut [f32],
+    ) -> Result<(NumFrames, NumFrames), ResamplerError> {
+        let mut data = SRC_DATA {
+            data_in: input.as_ptr(),
+            data_out: output.as_mut_ptr(),
+            input_frames: (input.len() / self.channels) as _,
+            output_frames: (output.len() / self.channels) as _,
+            src_ratio: self.ratio,
+            end_of_input: 0,
+            input_frames_used: 0,
+            output_frames_gen: 0,
+        };
+
+        match unsafe { src_process(self.state, &mut data) } {
+            0 => Ok((
+                NumFrames::new(data.input_frames_used as usize),
+                NumFrames::new(data.output_frames_gen as usize),
+            )),
+            code => Err(ResamplerError(error_message(code))),
+        }
+    }
+}
+
+impl Drop for Resampler {
+    fn drop(&mut self) {
+        unsafe { src_delete(self.state) };
+    }
+}
@@ -0,0 +1,202 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::sync::Arc;
+
+use symphonia::core::audio::Channels;
+
+use crate::{
+    error::{SymphoniaSourceError, ValueOutOfRangeError},
+    ext::Frames,
+    source::{
+        symphonia::{Looping, SymphoniaSource},
+        SourceOps,
+    },
+    types::{AudioSpec, NumFrames, StreamState},
+};
+
+/// A source playing from a shared, already decoded buffer of interleaved samples.
+///
+/// Cloning is cheap, so one buffer can be played any number of times concurrently.
+#[derive(Clone)]
+pub struct BufferSource {
+    spec: AudioSpec,
+    stream_state: StreamState,
+    data: Arc<[f32]>,
+    read_frame_offset: usize,
+    channel_layout: Option<Channels>,
+}
+
+impl std::fmt::Debug for BufferSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "BufferSource(spec: {:?}, stream_state: {:?}, frame {} of {})",
+            self.spec,
+            self.stream_state,
+            self.read_frame_offset,
+            self.data.len_frames(self.spec).get(),
+        ))
+    }
+}
+
+impl BufferSource {
+    pub fn new(spec: AudioSpec, data: Arc<[f32]>) -> Result<Self, ValueOutOfRangeError> {
+        if !data.len().is_multiple_of(spec.channels.get() as usize) {
+            return Err(ValueOutOfRangeError(
+                "Buffer length must be a multiple of the channel count".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            spec,
+            stream_state: if data.is_empty() {
+                StreamState::Complete
+            } else {
+                StreamState::Streaming
+            },
+            data,
+            read_frame_offset: 0,
+            channel_layout: None,
+        })
+    }
+
+    /// Decode all of `source` into a new buffer. Sources set to loop a number of times
+    /// have every repetition rendered into the buffer, while sources looping forever are
+    /// rejected, as are sources yielding no audio at all due to errors.
+    pub fn decode(mut source: SymphoniaSource) -> Result<Self, SymphoniaSourceError> {
+        if source.looping() == Looping::Forever {
+            return Err(SymphoniaSourceError(
+                "Can't decode a source looping forever".to_string(),
+            ));
+        }
+
+        let spec = source.spec();
+        let channel_layout = source.channel_layout();
+        let mut data = Vec::new();
+        let mut chunk = vec![0.0f32; spec.samplerate.get() as usize * spec.channels.get() as usize];
+
+        while source.stream_state() != StreamState::Complete {
+            chunk.fill(0.0);
+
+            let frames = source.mix_to_same_spec(&mut chunk);
+
+            if frames.get() == 0 && source.stream_state() != StreamState::Complete {
+                return Err(SymphoniaSourceError(
+                    "Source stopped producing audio before completing".to_string(),
+                ));
+            }
+
+            data.extend_from_slice(chunk.slice_frames(spec, ..frames.get()));
+        }
+
+        if data.is_empty() && source.decode_errors() > 0 {
+            return Err(SymphoniaSourceError(format!(
+                "No audio decoded, {} corrupt packets",
+                source.decode_errors()
+            )));
+        }
+
+        Ok(Self {
+            channel_layout,
+            ..Self::new(spec, data.into()).expect("Decoded data should hold whole frames")
+        })
+    }
+
+    /// The shared buffer played by this source.
+    pub fn data(&self) -> &Arc<[f32]> {
+        &self.data
+    }
+}
+
+impl SourceOps for BufferSource {
+    fn spec(&self) -> AudioSpec {
+        self.spec
+    }
+
+    fn stream_state(&self) -> StreamState {
+        self.stream_state
+    }
+
+    fn stream_position(&self) -> Option<NumFrames> {
+        Some(NumFrames::new(self.read_frame_offset))
+    }
+
+    fn channel_layout(&self) -> Option<Channels> {
+        self.channel_layout
+    }
+
+    fn mix_to_same_spec(&mut self, out_buffer: &mut [f32]) -> NumFrames {
+        let remaining = self.data.slice_frames(self.spec, self.read_frame_offset..);
+
+        out_buffer
+            .iter_mut()
+            .zip(remaining)
+            .for_each(|(output, sample)| *output += sample);
+
+        let frames_mixed = std::cmp::min(
+            out_buffer.len_frames(self.spec),
+            remaining.len_frames(self.spec),
+        );
+
+        self.read_frame_offset += frames_mixed.get();
+
+        if self.read_frame_offset == self.data.len_frames(self.spec).get() {
+            self.stream_state = StreamState::Complete;
+        }
+
+        frames_mixed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_source() {
+        let spec = AudioSpec::new(48000, 2).unwrap();
+        let data: Arc<[f32]> = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0].into();
+
+        assert!(BufferSource::new(spec, data[..5].into()).is_err());
+
+        let mut first = BufferSource::new(spec, data.clone()).unwrap();
+        let mut second = first.clone();
+        let mut buf = [0.0f32; 4];
+
+        assert_eq!(first.mix_to_same_spec(&mut buf), NumFrames::new(2));
+        assert_eq!(buf, [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(first.stream_state(), StreamState::Streaming);
+
+        assert_eq!(first.mix_to_same_spec(&mut buf), NumFrames::new(1));
+        assert_eq!(buf, [6.0, 8.0, 3.0, 4.0]);
+        assert_eq!(first.stream_state(), StreamState::Complete);
+
+        let mut buf = [0.0f32; 8];
+
+        assert_eq!(second.mix_to_same_spec(&mut buf), NumFrames::new(3));
+        assert_eq!(buf, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 0.0, 0.0]);
+        assert_eq!(second.stream_state(), StreamState::Complete);
+        assert_eq!(Arc::strong_count(&data), 3);
+    }
+
+    #[test]
+    fn test_decode_looping() {
+        let path = format!(
+            "{}/test_assets/square_1ch_48k_20smp.wav",
+            std::env::var("CARGO_MANIFEST_DIR").unwrap()
+        );
+
+        let twice = SymphoniaSource::from_file(&path)
+            .unwrap()
+            .with_looping(Looping::Times(2));
+
+        assert_eq!(BufferSource::decode(twice).unwrap().data().len(), 40);
+
+        let forever = SymphoniaSource::from_file(&path)
+            .unwrap()
+            .with_looping(Looping::Forever);
+
+        assert!(BufferSource::decode(forever).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use symphonia::core::audio::Channels;
+
+use crate::types::NumChannels;
+
+pub(crate) enum ChannelConversion {
+    /// Each output channel is a weighted sum of the input channels.
+    Matrix {
+        input_channels: usize,
+        output_channels: usize,
+
+        /// Row-major, one row of `input_channels` coefficients per output channel.
+        coefficients: Vec<f32>,
+    },
+}
+
+impl ChannelConversion {
+    /// Convert interleaved `input` into `output`, which must hold the same number of
+    /// frames in the output channel count.
+    pub fn convert(&self, input: &[f32], output: &mut [f32]) {
+        let ChannelConversion::Matrix {
+            input_channels,
+            output_channels,
+            coefficients,
+        } = self;
+
+        debug_assert_eq!(input.len() / input_channels, output.len() / output_channels);
+
+        for (in_frame, out_frame) in input
+            .chunks_exact(*input_channels)
+            .zip(output.chunks_exact_mut(*output_channels))
+        {
+            for (output, row) in out_frame
+                .iter_mut()
+                .zip(coefficients.chunks_exact(*input_channels))
+            {
+                *output = row
+                    .iter()
+                    .zip(in_frame.iter())
+                    .map(|(coef, sample)| coef * sample)
+                    .sum();
+            }
+        }
+    }
+}
+
+/// The conventional channel layout for the given number of channels, following the
+/// WAVE/SMPTE channel order. Returns `None` if there are more channels than speaker
+/// positions.
+pub(crate) fn default_layout(channels: NumChannels) -> Option<Channels> {
+    const FL: Channels = Channels::FRONT_LEFT;
+    const FR: Channels = Channels::FRONT_RIGHT;
+    const FC: Channels = Channels::FRONT_CENTRE;
+    const LFE: Channels = Channels::LFE1;
+    const RL: Channels = Channels::REAR_LEFT;
+    const RR: Channels = Channels::REAR_RIGHT;
+    const RC: Channels = Channels::REAR_CENTRE;
+    const SL: Channels = Channels::SIDE_LEFT;
+    const SR: Channels = Channels::SIDE_RIGHT;
+
+    let layout = match channels.get() {
+        1 => FC,
+        2 => FL | FR,
+        3 => FL | FR | FC,
+        4 => FL | FR | RL | RR,
+        5 => FL | FR | FC | RL | RR,
+        6 => FL | FR | FC | LFE | RL | RR,
+        7 => FL | FR | FC | LFE | RC | SL | SR,
+        8 => FL | FR | FC | LFE | RL | RR | SL | SR,
+        n if n as usize <= Channels::all().count() => {
+            Channels::from_bits_truncate(((1u64 << n) - 1) as u32)
+        }
+        _ => return None,
+    };
+
+    Some(layout)
+}
+
+/// Which side of the listener a speaker position is on.
+enum Side {
+    Left,
+    Right,
+    Centre,
+}
+
+fn side(position: Channels) -> Side {
+    const LEFT: Channels = Channels::FRONT_LEFT
+        .union(Channels::REAR_LEFT)
+        .union(Channels::FRONT_LEFT_CENTRE)
+        .union(Channels::SIDE_LEFT)
+        .union(Channels::TOP_FRONT_LEFT)
+        .union(Channels::TOP_REAR_LEFT)
+        .union(Channels::REAR_LEFT_CENTRE)
+        .union(Channels::FRONT_LEFT_WIDE)
+        .union(Channels::FRONT_LEFT_HIGH);
+
+    const RIGHT: Channels = Channels::FRONT_RIGHT
+        .union(Channels::REAR_RIGHT)
+        .union(Channels::FRONT_RIGHT_CENTRE)
+        .union(Channels::SIDE_RIGHT)
+        .union(Channels::TOP_FRONT_RIGHT)
+        .union(Channels::TOP_REAR_RIGHT)
+        .union(Channels::REAR_RIGHT_CENTRE)
+        .union(Channels::FRONT_RIGHT_WIDE)
+        .union(Channels::FRONT_RIGHT_HIGH);
+
+    if LEFT.contains(position) {
+        Side::Left
+    } else if RIGHT.contains(position) {
+        Side::Right
+    } else {
+        Side::Centre
+    }
+}
+
+/// Output positions and coefficients that an input position is mixed into.
+fn routing(position: Channels, output: Channels) -> Vec<(Channels, f32)> {
+    if output.contains(position) {
+        return vec![(position, 1.0)];
+    }
+
+    // surrounds fold into the other kind of surround on the same side if available
+    let alternate = match position {
+        Channels::REAR_LEFT => Some(Channels::SIDE_LEFT),
+        Channels::SIDE_LEFT => Some(Channels::REAR_LEFT),
+        Channels::REAR_RIGHT => Some(Channels::SIDE_RIGHT),
+        Channels::SIDE_RIGHT => Some(Channels::REAR_RIGHT),
+        _ => None,
+    };
+
+    if let Some(alternate) = alternate.filter(|alt| output.contains(*alt)) {
+        return vec![(alternate, 1.0)];
+    }
+
+    let front_pair = output.contains(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+
+    let targets = match (position, side(position)) {
+        (Channels::LFE1 | Channels::LFE2, _) => vec![],
+        (_, Side::Left) if output.contains(Channels::FRONT_LEFT) => vec![Channels::FRONT_LEFT],
+        (_, Side::Right) if output.contains(Channels::FRONT_RIGHT) => vec![Channels::FRONT_RIGHT],
+        (_, Side::Centre) if output.contains(Channels::FRONT_CENTRE) => {
+            vec![Channels::FRONT_CENTRE]
+        }
+        (_, Side::Centre) if front_pair => vec![Channels::FRONT_LEFT, Channels::FRONT_RIGHT],
+        _ if output.contains(Channels::FRONT_CENTRE) => vec![Channels::FRONT_CENTRE],
+        _ => vec![],
+    };
+
+    // folds are attenuated by 3 dB, which keeps equal power when spreading over a pair
+    targets
+        .into_iter()
+        .map(|target| (target, FRAC_1_SQRT_2))
+        .collect()
+}
+
+/// Symphonia describes mono as front-left, but it should be treated as centre.
+fn as_mono_centre(layout: Channels) -> Channels {
+    if layout.count() == 1 {
+        Channels::FRONT_CENTRE
+    } else {
+        layout
+    }
+}
+
+/// Build a conversion between channel layouts, using ITU-style downmix coefficients and
+/// equal-power upmixing of centre channels. Returns `None` if the layouts are equal.
+pub(crate) fn make_channel_conversion(
+    input_layout: Channels,
+    output_layout: Channels,
+) -> Option<ChannelConversion> {
+    let input_layout = as_mono_centre(input_layout);
+    let output_layout = as_mono_centre(output_layout);
+
+    if input_layout == output_layout {
+        return None;
+    }
+
+    let input_channels = input_layout.count();
+    let output_channels = output_layout.count();
+    let mut coefficients = vec![0.0f32; input_channels * output_channels];
+
+    for (in_index, position) in input_layout.iter().enumerate() {
+        for (target, coef) in routing(position, output_layout) {
+            let out_index = output_layout
+                .iter()
+                .position(|pos| pos == target)
+                .expect("Routing targets should be part of the output layout");
+
+            coefficients[out_index * input_channels + in_index] += coef;
+        }
+    }
+
+    Some(ChannelConversion::Matrix {
+        input_channels,
+        output_channels,
+        coefficients,
+    })
+}
+
+/// Build a conversion mapping each input channel to the output channel of the same
+/// index, for channels without speaker positions. Returns `None` if the channel counts
+/// are equal.
+pub(crate) fn make_discrete_conversion(
+    input_channels: NumChannels,
+    output_channels: NumChannels,
+) -> Option<ChannelConversion> {
+    if input_channels == output_channels {
+        return None;
+    }
+
+    let input_channels = input_channels.get() as usize;
+    let output_channels = output_channels.get() as usize;
+    let mut coefficients = vec![0.0f32; input_channels * output_channels];
+
+    for index in 0..input_channels.min(output_channels) {
+        coefficients[index * input_channels + index] = 1.0;
+    }
+
+    Some(ChannelConversion::Matrix {
+        input_channels,
+        output_channels,
+        coefficients,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(channels: u8) -> Channels {
+        default_layout(NumChannels::new(channels).unwrap()).unwrap()
+    }
+
+    fn convert(input_channels: u8, output_channels: u8, input: &[f32]) -> Vec<f32> {
+        let conv =
+            make_channel_conversion(layout(input_channels), layout(output_channels)).unwrap();
+
+        let mut output =
+            vec![0.0; input.len() / input_channels as usize * output_channels as usize];
+
+        conv.convert(input, &mut output);
+        output
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-6, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn test_identity() {
+        assert!(make_channel_conversion(layout(2), layout(2)).is_none());
+        assert!(make_channel_conversion(Channels::FRONT_LEFT, layout(1)).is_none());
+        assert!(make_channel_conversion(layout(6), layout(6)).is_none());
+    }
+
+    #[test]
+    fn test_mono_upmix() {
+        assert_close(
+            &convert(1, 2, &[1.0, 0.5]),
+            &[
+                FRAC_1_SQRT_2,
+                FRAC_1_SQRT_2,
+                0.5 * FRAC_1_SQRT_2,
+                0.5 * FRAC_1_SQRT_2,
+            ],
+        );
+
+        assert_close(&convert(1, 6, &[1.0]), &[0.0, 0.0, 1.0, 0.0, 0.0, 0.0]);
+
+        let conv = make_channel_conversion(Channels::FRONT_LEFT, layout(2)).unwrap();
+        let mut output = [0.0; 2];
+
+        conv.convert(&[1.0], &mut output);
+        assert_close(&output, &[FRAC_1_SQRT_2, FRAC_1_SQRT_2]);
+    }
+
+    #[test]
+    fn test_many_channels() {
+        assert_eq!(layout(26).count(), 26);
+        assert_eq!(default_layout(NumChannels::new(27).unwrap()), None);
+        assert_eq!(default_layout(NumChannels::new(255).unwrap()), None);
+
+        let channels = |n| NumChannels::new(n).unwrap();
+
+        assert!(make_discrete_conversion(channels(64), channels(64)).is_none());
+
+        let conv = make_discrete_conversion(channels(64), channels(2)).unwrap();
+        let input = (0..64).map(|x| x as f32).collect::<Vec<_>>();
+        let mut output = [0.0; 2];
+
+        conv.convert(&input, &mut output);
+        assert_close(&output, &[0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_stereo_downmix() {
+        assert_close(&convert(2, 1, &[1.0, 0.5]), &[1.5 * FRAC_1_SQRT_2]);
+    }
+
+    #[test]
+    fn test_surround_downmix() {
+        // FL FR FC LFE RL RR
+        assert_close(
+            &convert(6, 2, &[1.0, 0.0, 1.0, 1.0, 1.0, 0.0]),
+            &[1.0 + 2.0 * FRAC_1_SQRT_2, FRAC_1_SQRT_2],
+        );
+
+        // FL FR FC LFE RL RR SL SR
+        assert_close(
+            &convert(8, 6, &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8]),
+            &[0.1, 0.2, 0.3, 0.4, 1.2, 1.4],
+        );
+
+        // FL FR FC
+        assert_close(
+            &convert(3, 2, &[1.0, 0.0, 1.0]),
+            &[1.0 + FRAC_1_SQRT_2, FRAC_1_SQRT_2],
+        );
+    }
+}
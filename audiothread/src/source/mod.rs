@@ -2,28 +2,59 @@
 //
 // Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
 
+use ::symphonia::core::audio::Channels;
 use ringbuf::{
     traits::{Consumer, Observer, Producer},
     HeapRb,
 };
-use samplerate::{ConverterType, Samplerate as SamplerateConverter};
 
 use crate::{
     error::MismatchedSpecError,
-    ext::{BufferIteratorOps, Frames},
-    types::{AudioSpec, NumChannels, Quality, StreamState},
+    ext::Frames,
+    resampler::Resampler,
+    types::{AudioSpec, FadeLength, NumFrames, Quality, SourceId, StreamState},
 };
 
+pub(crate) mod buffer;
+pub(crate) mod channels;
 pub(crate) mod pulled;
+pub(crate) mod queue;
 pub(crate) mod symphonia;
+pub(crate) mod voice;
 
+use buffer::BufferSource;
 use pulled::PulledSource;
+use queue::QueueSource;
 use symphonia::SymphoniaSource;
+use voice::{Voice, VoiceScratch};
+
+/// Factor by which the source frames mixed for the first sample rate conversion are
+/// overestimated, see the FIXME in `SourceGroup::mix_chunk`.
+const FIRST_CONVERSION_MARGIN: f64 = 1.5;
 
 pub(crate) trait SourceOps {
     fn spec(&self) -> AudioSpec;
+
+    /// Name given to the source when it was set up, if it has one.
+    fn name(&self) -> Option<&str> {
+        None
+    }
     fn stream_state(&self) -> StreamState;
-    fn mix_to_same_spec(&mut self, buffer: &mut [f32]);
+
+    /// Position of the next frame to be mixed within the underlying file or buffer, if
+    /// the source has one.
+    fn stream_position(&self) -> Option<NumFrames>;
+
+    /// Speaker positions of the source's channels, if known.
+    fn channel_layout(&self) -> Option<Channels>;
+
+    /// Number of corrupt parts of the stream skipped so far.
+    fn decode_errors(&self) -> u64 {
+        0
+    }
+
+    /// Mix into a buffer of the source's own spec, returning the number of frames mixed.
+    fn mix_to_same_spec(&mut self, buffer: &mut [f32]) -> NumFrames;
 }
 
 #[cfg(test)]
@@ -45,7 +76,15 @@ impl SourceOps for FakeSource {
         self.stream_state
     }
 
-    fn mix_to_same_spec(&mut self, out_buffer: &mut [f32]) {
+    fn stream_position(&self) -> Option<NumFrames> {
+        Some(NumFrames::new(self.read_frame_offset))
+    }
+
+    fn channel_layout(&self) -> Option<Channels> {
+        None
+    }
+
+    fn mix_to_same_spec(&mut self, out_buffer: &mut [f32]) -> NumFrames {
         let self_offset_buffer = self
             .buffer
             .slice_frames(self.spec, self.read_frame_offset..);
@@ -55,17 +94,20 @@ impl SourceOps for FakeSource {
             .zip(self_offset_buffer)
             .for_each(|(output, sample)| *output += sample);
 
-        self.read_frame_offset += std::cmp::min(
+        let frames_mixed = std::cmp::min(
             out_buffer.len_frames(self.spec),
             self_offset_buffer.len_frames(self.spec),
-        )
-        .get();
+        );
+
+        self.read_frame_offset += frames_mixed.get();
 
         debug_assert!(self.read_frame_offset <= self.buffer.len_frames(self.spec).get());
 
         if self.read_frame_offset == self.buffer.len_frames(self.spec).get() {
             self.stream_state = StreamState::Complete;
         }
+
+        frames_mixed
     }
 }
 
@@ -78,6 +120,8 @@ pub(crate) enum Source {
 
     SymphoniaSource(SymphoniaSource),
     PulledSource(PulledSource),
+    BufferSource(BufferSource),
+    QueueSource(QueueSource),
 }
 
 impl SourceOps for Source {
@@ -88,6 +132,20 @@ impl SourceOps for Source {
 
             Source::SymphoniaSource(source) => source.spec(),
             Source::PulledSource(source) => source.spec(),
+            Source::BufferSource(source) => source.spec(),
+            Source::QueueSource(source) => source.spec(),
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        match self {
+            #[cfg(test)]
+            Source::FakeSource(source) => source.name(),
+
+            Source::SymphoniaSource(source) => source.name(),
+            Source::PulledSource(source) => source.name(),
+            Source::BufferSource(source) => source.name(),
+            Source::QueueSource(source) => source.name(),
         }
     }
 
@@ -98,34 +156,74 @@ impl SourceOps for Source {
 
             Source::SymphoniaSource(source) => source.stream_state(),
             Source::PulledSource(source) => source.stream_state(),
+            Source::BufferSource(source) => source.stream_state(),
+            Source::QueueSource(source) => source.stream_state(),
+        }
+    }
+
+    fn stream_position(&self) -> Option<NumFrames> {
+        match self {
+            #[cfg(test)]
+            Source::FakeSource(source) => source.stream_position(),
+
+            Source::SymphoniaSource(source) => source.stream_position(),
+            Source::PulledSource(source) => source.stream_position(),
+            Source::BufferSource(source) => source.stream_position(),
+            Source::QueueSource(source) => source.stream_position(),
+        }
+    }
+
+    fn channel_layout(&self) -> Option<Channels> {
+        match self {
+            #[cfg(test)]
+            Source::FakeSource(source) => source.channel_layout(),
+
+            Source::SymphoniaSource(source) => source.channel_layout(),
+            Source::PulledSource(source) => source.channel_layout(),
+            Source::BufferSource(source) => source.channel_layout(),
+            Source::QueueSource(source) => source.channel_layout(),
+        }
+    }
+
+    fn decode_errors(&self) -> u64 {
+        match self {
+            #[cfg(test)]
+            Source::FakeSource(source) => source.decode_errors(),
+
+            Source::SymphoniaSource(source) => source.decode_errors(),
+            Source::PulledSource(source) => source.decode_errors(),
+            Source::BufferSource(source) => source.decode_errors(),
+            Source::QueueSource(source) => source.decode_errors(),
         }
     }
 
-    fn mix_to_same_spec(&mut self, buffer: &mut [f32]) {
+    fn mix_to_same_spec(&mut self, buffer: &mut [f32]) -> NumFrames {
         match self {
             #[cfg(test)]
             Source::FakeSource(source) => source.mix_to_same_spec(buffer),
 
             Source::SymphoniaSource(source) => source.mix_to_same_spec(buffer),
             Source::PulledSource(source) => source.mix_to_same_spec(buffer),
+            Source::BufferSource(source) => source.mix_to_same_spec(buffer),
+            Source::QueueSource(source) => source.mix_to_same_spec(buffer),
         }
     }
 }
 
-enum ChannelConversion {
-    DuplicateAndOrTruncate {
-        input_channels: NumChannels,
-        output_channels: NumChannels,
-    },
-}
-
 pub(crate) struct SourceGroup {
     spec: AudioSpec,
-    sources: Vec<Source>,
-    channel_conv: Option<ChannelConversion>,
-    samplerate_conv: Option<samplerate::samplerate::Samplerate>,
+    voices: Vec<Voice>,
+    mix_spec: AudioSpec,
+    samplerate_conv: Option<Resampler>,
     samplerate_conv_done_once: bool,
+    voice_scratch: VoiceScratch,
+
+    /// Most output frames mixed in one go, larger buffers are mixed in chunks of this
+    /// size so that the preallocated buffers always suffice.
+    max_chunk_frames: usize,
+
     pre_conv_buf: Vec<f32>,
+    post_conv_buf: Vec<f32>,
     post_conv_overflow_buf: ringbuf::HeapRb<f32>,
 }
 
@@ -135,30 +233,61 @@ impl SourceGroup {
         output_spec: AudioSpec,
         conversion_quality: Quality,
     ) -> Self {
+        // voices are converted to the output channel count before being mixed
+        let mix_spec = AudioSpec {
+            samplerate: source_spec.samplerate,
+            channels: output_spec.channels,
+        };
+
+        let samplerate_conv = (source_spec.samplerate != output_spec.samplerate).then(|| {
+            Resampler::new(
+                conversion_quality,
+                source_spec.samplerate,
+                output_spec.samplerate,
+                output_spec.channels,
+            )
+            .expect("Sample rate converter should be created")
+        });
+
+        // everything needed while mixing is allocated up front, one second of audio
+        // before conversion and (with margin) whatever that converts into
+        let max_mix_frames = mix_spec.samplerate.get() as usize;
+        let max_converted_samples = samplerate_conv.as_ref().map_or(0, |conv| {
+            (max_mix_frames as f64 * conv.ratio()).ceil() as usize
+                * 2
+                * output_spec.channels.get() as usize
+        });
+
+        // leaves room for the extra frames asked for on the first conversion
+        let max_chunk_frames = samplerate_conv.as_ref().map_or(max_mix_frames, |conv| {
+            ((max_mix_frames - 1) as f64 * conv.ratio() / FIRST_CONVERSION_MARGIN) as usize
+        });
+
         Self {
             spec: source_spec,
-            sources: Vec::new(),
-            channel_conv: make_channel_conversion(source_spec, output_spec),
-            samplerate_conv: make_rate_conversion(source_spec, output_spec, conversion_quality),
+            voices: Vec::new(),
+            mix_spec,
+            samplerate_conv,
             samplerate_conv_done_once: false,
-            pre_conv_buf: vec![
-                0.0f32;
-                (source_spec.channels.get() as usize)
-                    * (source_spec.samplerate.get() as usize)
-            ],
-            post_conv_overflow_buf: HeapRb::new(
-                (source_spec.channels.get() as usize) * (source_spec.samplerate.get() as usize),
-            ),
+            voice_scratch: VoiceScratch::new(source_spec, output_spec.channels, max_mix_frames),
+            max_chunk_frames: max_chunk_frames.max(1),
+            pre_conv_buf: vec![0.0f32; max_mix_frames * mix_spec.channels.get() as usize],
+            post_conv_buf: vec![0.0f32; max_converted_samples],
+            post_conv_overflow_buf: HeapRb::new(max_converted_samples.max(1)),
         }
     }
 
-    pub fn sources_iter_mut(&mut self) -> impl Iterator<Item = &mut Source> {
-        self.sources.iter_mut()
+    pub fn voices_iter(&self) -> impl Iterator<Item = &Voice> {
+        self.voices.iter()
+    }
+
+    pub fn voices_iter_mut(&mut self) -> impl Iterator<Item = &mut Voice> {
+        self.voices.iter_mut()
     }
 
-    pub fn add_source(&mut self, source: Source) -> Result<(), MismatchedSpecError> {
-        if self.spec == source.spec() {
-            self.sources.push(source);
+    pub fn add_voice(&mut self, voice: Voice) -> Result<(), MismatchedSpecError> {
+        if self.spec == voice.spec() {
+            self.voices.push(voice);
             Ok(())
         } else {
             Err(MismatchedSpecError)
@@ -166,30 +295,76 @@ impl SourceGroup {
     }
 
     pub fn drop_completed_sources(&mut self) {
-        self.sources
-            .retain(|source| source.stream_state() != StreamState::Complete)
+        self.voices.retain(|voice| !voice.is_done())
+    }
+
+    /// Drop all sources for which `predicate` holds, either immediately or once they
+    /// have been faded out.
+    fn drop_sources_where(
+        &mut self,
+        predicate: impl Fn(&Voice) -> bool,
+        fade_out: Option<FadeLength>,
+    ) {
+        match fade_out {
+            Some(length) => {
+                let frames = length.frames(self.spec.samplerate);
+
+                self.voices
+                    .iter_mut()
+                    .filter(|voice| predicate(voice))
+                    .for_each(|voice| voice.fade_out(frames));
+            }
+            None => self.voices.retain(|voice| !predicate(voice)),
+        }
+    }
+
+    pub fn drop_all_sources(&mut self, fade_out: Option<FadeLength>) {
+        self.drop_sources_where(|_| true, fade_out)
     }
 
-    pub fn drop_matching_sources(&mut self, matcher: &SourceMatcher) {
-        self.sources.retain(|source| !matcher.matches(source))
+    pub fn drop_matching_sources(&mut self, matcher: &SourceMatcher, fade_out: Option<FadeLength>) {
+        self.drop_sources_where(|voice| matcher.matches(voice), fade_out)
+    }
+
+    pub fn drop_source(&mut self, id: SourceId, fade_out: Option<FadeLength>) {
+        self.drop_sources_where(|voice| voice.id() == id, fade_out)
     }
 
     pub fn sources_len(&self) -> usize {
-        self.sources.len()
+        self.voices.len()
     }
 
-    pub fn mix_to_given_spec(&mut self, out_spec: AudioSpec, out_buffer: &mut [f32]) {
-        if self.sources.is_empty() {
+    /// Mix all voices into `out_buffer`, whose first frame is at `stream_time` in the
+    /// output stream. Voices scheduled to start later are started at the frame where
+    /// their start time falls, up to the latency of any sample rate conversion.
+    pub fn mix_to_given_spec(
+        &mut self,
+        out_spec: AudioSpec,
+        out_buffer: &mut [f32],
+        stream_time: NumFrames,
+    ) {
+        if self.voices.is_empty() {
             return;
         }
 
-        let self_chans = self.spec.channels.get() as usize;
+        let chunk_samples = self.max_chunk_frames * out_spec.channels.get() as usize;
+
+        for (n, chunk) in out_buffer.chunks_mut(chunk_samples).enumerate() {
+            let chunk_time = NumFrames::new(stream_time.get() + n * self.max_chunk_frames);
+            self.mix_chunk(out_spec, chunk, chunk_time);
+        }
+    }
+
+    fn mix_chunk(&mut self, out_spec: AudioSpec, out_buffer: &mut [f32], stream_time: NumFrames) {
         let out_chans = out_spec.channels.get() as usize;
 
-        debug_assert!(out_buffer.len() % out_chans == 0);
+        debug_assert!(out_buffer.len().is_multiple_of(out_chans));
 
         // TODO: implement Frames for HeapRb / SharedRb<Heap<f32>>
-        debug_assert!(self.post_conv_overflow_buf.occupied_len() % out_chans == 0);
+        debug_assert!(self
+            .post_conv_overflow_buf
+            .occupied_len()
+            .is_multiple_of(out_chans));
 
         let num_out_buffer_frames = out_buffer.len_frames(out_spec).get();
 
@@ -215,8 +390,8 @@ impl SourceGroup {
 
         // FIXME: hack for libsamplerate's "transport delay" which means the first call
         //        to .process() may return fewer frames than expected.
-        if !self.samplerate_conv_done_once && self.spec != out_spec {
-            source_spec_frames_needed *= 1.5;
+        if !self.samplerate_conv_done_once && self.samplerate_conv.is_some() {
+            source_spec_frames_needed *= FIRST_CONVERSION_MARGIN;
             self.samplerate_conv_done_once = true;
         }
 
@@ -224,46 +399,46 @@ impl SourceGroup {
 
         let mixbuf = self
             .pre_conv_buf
-            .slice_frames_mut(self.spec, ..source_spec_frames_needed_ceil);
+            .slice_frames_mut(self.mix_spec, ..source_spec_frames_needed_ceil);
 
         mixbuf.fill(0.0f32);
 
-        for source in self.sources.iter_mut() {
-            source.mix_to_same_spec(mixbuf);
-        }
+        // output stream time of the first frame mixed below
+        let mix_start_time = stream_time.get() + prior_overflow_frames_drained;
+        let mix_frames = mixbuf.len_frames(self.mix_spec).get();
 
-        let mut mixbuf_iter: Box<dyn Iterator<Item = f32>> = Box::new(mixbuf.iter().copied());
+        for voice in self.voices.iter_mut() {
+            let start_offset = match voice.start_time() {
+                Some(start_time) if !voice.is_paused() => {
+                    let out_frames_until_start = start_time.get().saturating_sub(mix_start_time);
 
-        if let Some(ChannelConversion::DuplicateAndOrTruncate {
-            input_channels,
-            output_channels,
-        }) = &self.channel_conv
-        {
-            let mut num_channels = input_channels.get();
+                    let offset = (out_frames_until_start as f64
+                        * (self.spec.samplerate.get() as f64 / out_spec.samplerate.get() as f64))
+                        .round() as usize;
 
-            debug_assert_eq!(self_chans, num_channels as usize);
+                    if offset >= mix_frames {
+                        continue;
+                    }
 
-            while num_channels < output_channels.get() {
-                mixbuf_iter = Box::new(mixbuf_iter.doubled());
-                num_channels *= 2;
-            }
+                    voice.set_started();
+                    offset
+                }
+                _ => 0,
+            };
 
-            if num_channels > output_channels.get() {
-                mixbuf_iter = Box::new(
-                    mixbuf_iter
-                        .drop_channels(num_channels as usize, output_channels.get() as usize),
-                );
-            }
+            voice.mix_to_output_channels(
+                mixbuf.slice_frames_mut(self.mix_spec, start_offset..),
+                &mut self.voice_scratch,
+            );
         }
 
-        if let Some(converter) = &self.samplerate_conv {
-            let converted_samples = converter
-                .process(mixbuf_iter.collect::<Vec<_>>().as_slice())
-                .unwrap();
-
-            debug_assert!(converted_samples.len() % out_chans == 0);
-
-            let num_converted_frames = converted_samples.len_frames(out_spec).get();
+        if let Some(converter) = &mut self.samplerate_conv {
+            let (_, num_converted_frames) =
+                converter.process(mixbuf, &mut self.post_conv_buf).unwrap();
+            let num_converted_frames = num_converted_frames.get();
+            let converted_samples = self
+                .post_conv_buf
+                .slice_frames(out_spec, ..num_converted_frames);
 
             debug_assert!(num_converted_frames >= out_spec_frames_needed);
 
@@ -283,60 +458,37 @@ impl SourceGroup {
             out_buffer
                 .slice_frames_mut(out_spec, prior_overflow_frames_drained..)
                 .iter_mut()
-                .zip(mixbuf_iter)
+                .zip(mixbuf.iter())
                 .for_each(|(output, sample)| *output += sample);
         }
     }
 }
 
-fn make_channel_conversion(
-    input_spec: AudioSpec,
-    output_spec: AudioSpec,
-) -> Option<ChannelConversion> {
-    match input_spec.channels.cmp(&output_spec.channels) {
-        std::cmp::Ordering::Less | std::cmp::Ordering::Greater => {
-            Some(ChannelConversion::DuplicateAndOrTruncate {
-                input_channels: input_spec.channels,
-                output_channels: output_spec.channels,
-            })
-        }
-        std::cmp::Ordering::Equal => None,
-    }
-}
-
-fn make_rate_conversion(
-    input_spec: AudioSpec,
-    output_spec: AudioSpec,
-    quality: Quality,
-) -> Option<SamplerateConverter> {
-    match input_spec.samplerate.cmp(&output_spec.samplerate) {
-        std::cmp::Ordering::Less | std::cmp::Ordering::Greater => Some(
-            SamplerateConverter::new(
-                match quality {
-                    Quality::Lowest => ConverterType::Linear,
-                    Quality::Low => ConverterType::SincFastest,
-                    Quality::Medium => ConverterType::SincMediumQuality,
-                    Quality::High => ConverterType::SincBestQuality,
-                },
-                input_spec.samplerate.get(),
-                output_spec.samplerate.get(),
-                output_spec.channels.get() as usize,
-            )
-            .unwrap(),
-        ),
-        std::cmp::Ordering::Equal => None,
-    }
-}
-
 #[derive(Debug, Clone)]
 pub enum SourceType {
     SymphoniaSource,
     PulledSource,
+    BufferSource,
+    QueueSource,
+}
+
+#[derive(Debug, Clone)]
+enum NameMatch {
+    Exact(String),
+    Prefix(String),
 }
 
+/// Selects sources by criteria that all have to hold. A matcher without any criteria
+/// matches all sources.
 #[derive(Debug, Clone, Default)]
 pub struct SourceMatcher {
     typ: Option<SourceType>,
+    bus: Option<String>,
+    id: Option<SourceId>,
+    name: Option<NameMatch>,
+    spec: Option<AudioSpec>,
+    tag: Option<String>,
+    started_before: Option<NumFrames>,
 }
 
 impl SourceMatcher {
@@ -344,7 +496,6 @@ impl SourceMatcher {
         Self::default()
     }
 
-    #[allow(clippy::needless_update)]
     pub fn match_type(self, typ: SourceType) -> Self {
         Self {
             typ: Some(typ),
@@ -352,11 +503,96 @@ impl SourceMatcher {
         }
     }
 
-    fn matches(&self, source: &Source) -> bool {
+    /// Match sources playing on the named bus.
+    pub fn match_bus(self, bus: impl Into<String>) -> Self {
+        Self {
+            bus: Some(bus.into()),
+            ..self
+        }
+    }
+
+    pub fn match_id(self, id: SourceId) -> Self {
+        Self {
+            id: Some(id),
+            ..self
+        }
+    }
+
+    /// Match sources with the given name, such as the name of a [`PulledSourceSetup`].
+    ///
+    /// [`PulledSourceSetup`]: crate::PulledSourceSetup
+    pub fn match_name(self, name: impl Into<String>) -> Self {
+        Self {
+            name: Some(NameMatch::Exact(name.into())),
+            ..self
+        }
+    }
+
+    /// Match sources with a name starting with `prefix`.
+    pub fn match_name_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            name: Some(NameMatch::Prefix(prefix.into())),
+            ..self
+        }
+    }
+
+    pub fn match_spec(self, spec: AudioSpec) -> Self {
+        Self {
+            spec: Some(spec),
+            ..self
+        }
+    }
+
+    /// Match sources played with the given tag, see [`PlayOpts::with_tag`].
+    ///
+    /// [`PlayOpts::with_tag`]: crate::PlayOpts::with_tag
+    pub fn match_tag(self, tag: impl Into<String>) -> Self {
+        Self {
+            tag: Some(tag.into()),
+            ..self
+        }
+    }
+
+    /// Match sources started, or scheduled to start, before the given output stream time
+    /// (see [`crate::Message::GetStreamTime`]).
+    pub fn match_started_before(self, stream_time: NumFrames) -> Self {
+        Self {
+            started_before: Some(stream_time),
+            ..self
+        }
+    }
+
+    pub(crate) fn matches(&self, voice: &Voice) -> bool {
+        if self.bus.as_ref().is_some_and(|bus| bus != voice.bus())
+            || self.id.is_some_and(|id| id != voice.id())
+            || self.spec.is_some_and(|spec| spec != voice.spec())
+            || self
+                .tag
+                .as_deref()
+                .is_some_and(|tag| Some(tag) != voice.tag())
+            || self
+                .started_before
+                .is_some_and(|time| voice.play_time() >= time)
+        {
+            return false;
+        }
+
+        let name = voice.source().name();
+
+        match &self.name {
+            Some(NameMatch::Exact(expected)) if name != Some(expected.as_str()) => return false,
+            Some(NameMatch::Prefix(prefix)) if !name.is_some_and(|n| n.starts_with(prefix)) => {
+                return false
+            }
+            _ => (),
+        }
+
         if let Some(typ) = &self.typ {
-            match (typ, source) {
+            match (typ, voice.source()) {
                 (SourceType::SymphoniaSource, Source::SymphoniaSource(_)) => (),
                 (SourceType::PulledSource, Source::PulledSource(_)) => (),
+                (SourceType::BufferSource, Source::BufferSource(_)) => (),
+                (SourceType::QueueSource, Source::QueueSource(_)) => (),
                 _ => return false,
             };
         }
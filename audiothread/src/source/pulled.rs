@@ -9,13 +9,32 @@ use ringbuf::{
     HeapCons,
 };
 
+use symphonia::core::audio::Channels;
+
 use crate::{
     source::SourceOps,
-    types::{AudioSpec, NumFrames, StreamState},
+    types::{AudioSpec, NumFrames, Samplerate, StreamState},
 };
 
+/// Fill level below which a pulled source asks for more audio, unless configured with
+/// [`PulledSourceSetup::with_low_water_mark`].
+const DEFAULT_LOW_WATER_MARK: f32 = 0.5;
+
+/// A request for more audio, sent once the buffer of a pulled source falls below its
+/// low-water mark or runs empty.
 #[derive(Debug, Clone)]
 pub struct PulledSourcePullRequest {
+    /// Number of frames that fit in the buffer, in the spec of the source.
+    pub frames: NumFrames,
+
+    /// Output stream time at which the first of the requested frames is to be heard,
+    /// going by the frames already buffered and assuming playback at the normal rate.
+    pub stream_time: NumFrames,
+
+    /// Number of times the source has run out of audio while streaming, in total since
+    /// it was created. Running out before any audio has been provided doesn't count.
+    pub underruns: u64,
+
     pub response_tx: Sender<PulledSourcePullReply>,
 }
 
@@ -30,15 +49,17 @@ pub struct PulledSourceSetup {
     pub spec: AudioSpec,
     pub buffer_rx: HeapCons<f32>,
     pub pull_request_tx: Sender<PulledSourcePullRequest>,
+    pub(crate) low_water_mark: f32,
 }
 
 impl std::fmt::Debug for PulledSourceSetup {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&format!(
-            "PulledSourceSetup(name={}, spec={:?}, buffer capacity: {})",
+            "PulledSourceSetup(name={}, spec={:?}, buffer capacity: {}, low-water mark: {})",
             self.name,
             self.spec,
-            self.buffer_rx.capacity()
+            self.buffer_rx.capacity(),
+            self.low_water_mark,
         ))
     }
 }
@@ -55,6 +76,17 @@ impl PulledSourceSetup {
             spec,
             buffer_rx,
             pull_request_tx,
+            low_water_mark: DEFAULT_LOW_WATER_MARK,
+        }
+    }
+
+    /// Ask for more audio once the buffer is filled to less than `fraction` of its
+    /// capacity, clamped to 0.0..=1.0. At 0.0, audio is only asked for once the buffer
+    /// has run empty.
+    pub fn with_low_water_mark(self, fraction: f32) -> Self {
+        Self {
+            low_water_mark: fraction.clamp(0.0, 1.0),
+            ..self
         }
     }
 }
@@ -68,19 +100,30 @@ pub(crate) struct PulledSource {
     pull_response_tx: Sender<PulledSourcePullReply>,
     pull_response_rx: Receiver<PulledSourcePullReply>,
     pull_req_pending: bool,
+    low_water_mark: f32,
+    underruns: u64,
+
+    /// Whether any audio has been received, before which running out doesn't count as
+    /// an underrun.
+    received_audio: bool,
+
+    /// Whether the buffer has run out and no audio has arrived since, so that a stall
+    /// spanning several calls to mix counts as one underrun.
+    starved: bool,
 }
 
 impl std::fmt::Debug for PulledSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&format!(
             "PulledSource(name={}, spec={:?}, stream_state={:?}, buffer: {} of {}, \
-                pending pull request: {})",
+                pending pull request: {}, underruns: {})",
             self.name,
             self.spec,
             self.stream_state,
             self.buffer_rx.occupied_len(),
             self.buffer_rx.capacity(),
             self.pull_req_pending,
+            self.underruns,
         ))
     }
 }
@@ -97,10 +140,17 @@ impl PulledSource {
             pull_response_tx,
             pull_response_rx,
             pull_req_pending: false,
+            low_water_mark: setup.low_water_mark,
+            underruns: 0,
+            received_audio: false,
+            starved: false,
         }
     }
 
-    pub fn update(&mut self) {
+    /// Handle replies from the producer and ask it for more audio if needed. The
+    /// buffered audio is to be heard starting at output stream time `play_time`, at an
+    /// output sample rate of `output_rate`.
+    pub fn update(&mut self, play_time: NumFrames, output_rate: Samplerate) {
         if let StreamState::Streaming = self.stream_state {
             if self.pull_req_pending {
                 match self.pull_response_rx.try_recv() {
@@ -127,8 +177,8 @@ impl PulledSource {
                         }
                     },
                 }
-            } else if self.fraction_filled() < 0.5 {
-                self.send_pull_request();
+            } else if self.buffer_rx.is_empty() || self.fraction_filled() < self.low_water_mark {
+                self.send_pull_request(play_time, output_rate);
             }
         }
     }
@@ -137,8 +187,17 @@ impl PulledSource {
         self.buffer_rx.occupied_len() as f32 / self.buffer_rx.capacity().get() as f32
     }
 
-    fn send_pull_request(&mut self) {
+    fn send_pull_request(&mut self, play_time: NumFrames, output_rate: Samplerate) {
+        let chans = self.spec.channels.get() as usize;
+        let buffered_frames = self.buffer_rx.occupied_len() / chans;
+        let buffered_output_frames = (buffered_frames as f64 * output_rate.get() as f64
+            / self.spec.samplerate.get() as f64)
+            .round() as usize;
+
         match self.pull_request_tx.send(PulledSourcePullRequest {
+            frames: NumFrames::new(self.buffer_rx.vacant_len() / chans),
+            stream_time: NumFrames::new(play_time.get() + buffered_output_frames),
+            underruns: self.underruns,
             response_tx: self.pull_response_tx.clone(),
         }) {
             Ok(_) => self.pull_req_pending = true,
@@ -159,18 +218,89 @@ impl SourceOps for PulledSource {
         self.spec
     }
 
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
     fn stream_state(&self) -> StreamState {
         self.stream_state
     }
 
-    fn mix_to_same_spec(&mut self, out_buffer: &mut [f32]) {
+    fn stream_position(&self) -> Option<NumFrames> {
+        None
+    }
+
+    fn channel_layout(&self) -> Option<Channels> {
+        None
+    }
+
+    fn mix_to_same_spec(&mut self, out_buffer: &mut [f32]) -> NumFrames {
         let self_chans = self.spec.channels.get() as usize;
 
-        debug_assert!(self.buffer_rx.occupied_len() % self_chans == 0);
+        debug_assert!(self.buffer_rx.occupied_len().is_multiple_of(self_chans));
+
+        let samples_mixed = std::cmp::min(out_buffer.len(), self.buffer_rx.occupied_len());
+
+        if samples_mixed > 0 {
+            self.received_audio = true;
+            self.starved = false;
+        }
+
+        if samples_mixed < out_buffer.len()
+            && self.received_audio
+            && !self.starved
+            && self.stream_state == StreamState::Streaming
+        {
+            self.underruns += 1;
+            self.starved = true;
+        }
 
         out_buffer
             .iter_mut()
             .zip(self.buffer_rx.pop_iter())
             .for_each(|(output, sample)| *output += sample);
+
+        NumFrames::new(samples_mixed / self_chans)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::{traits::*, HeapRb};
+
+    use super::*;
+
+    #[test]
+    fn test_underruns() {
+        let spec = AudioSpec::new(48000, 1).unwrap();
+        let (mut buffer_tx, buffer_rx) = HeapRb::<f32>::new(8).split();
+        let (pull_request_tx, _pull_request_rx) = channel();
+        let mut source = PulledSource::from_setup(PulledSourceSetup::new(
+            "test",
+            spec,
+            buffer_rx,
+            pull_request_tx,
+        ));
+        let mut buf = [0.0; 4];
+
+        // running out before any audio has been provided doesn't count
+        source.mix_to_same_spec(&mut buf);
+        assert_eq!(source.underruns, 0);
+
+        buffer_tx.push_slice(&[1.0; 4]);
+        assert_eq!(source.mix_to_same_spec(&mut buf), NumFrames::new(4));
+        assert_eq!(source.underruns, 0);
+
+        // a stall spanning several calls counts once
+        for _ in 0..3 {
+            assert_eq!(source.mix_to_same_spec(&mut buf), NumFrames::new(0));
+        }
+
+        assert_eq!(source.underruns, 1);
+
+        buffer_tx.push_slice(&[1.0; 2]);
+        assert_eq!(source.mix_to_same_spec(&mut buf), NumFrames::new(2));
+        assert_eq!(source.mix_to_same_spec(&mut buf), NumFrames::new(0));
+        assert_eq!(source.underruns, 2);
     }
 }
@@ -0,0 +1,376 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::collections::VecDeque;
+
+use symphonia::core::audio::Channels;
+
+use crate::{
+    error::MismatchedSpecError,
+    source::{buffer::BufferSource, symphonia::SymphoniaSource, Source, SourceOps},
+    types::{AudioSpec, FadeLength, NumFrames, SourceId, StreamState},
+};
+
+/// A source that can be played as part of a [`QueueSource`].
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum QueueItem {
+    SymphoniaSource(SymphoniaSource),
+    BufferSource(BufferSource),
+}
+
+impl QueueItem {
+    fn into_source(self) -> Source {
+        match self {
+            QueueItem::SymphoniaSource(source) => Source::SymphoniaSource(source),
+            QueueItem::BufferSource(source) => Source::BufferSource(source),
+        }
+    }
+}
+
+/// A source playing a queue of items back to back, each starting on the frame after the
+/// previous one ends, or overlapping it by the length of the crossfade if one is set.
+///
+/// Items must have the spec of the queue. An item is reported finished with
+/// [`crate::StatusMessage::QueueItemFinished`] once all of it has been read, which with
+/// a crossfade is up to one crossfade length ahead of it being heard.
+pub struct QueueSource {
+    spec: AudioSpec,
+    stream_state: StreamState,
+    crossfade_frames: usize,
+    wait_when_empty: bool,
+    items: VecDeque<(SourceId, Box<Source>)>,
+    current: Option<(SourceId, Box<Source>)>,
+
+    /// Frames read ahead of the output, holding the last `crossfade_frames` frames of the
+    /// current item so that they can be faded into the next one.
+    lookahead: Vec<f32>,
+    lookahead_frames: usize,
+
+    scratch: Vec<f32>,
+    finished: Vec<SourceId>,
+}
+
+impl std::fmt::Debug for QueueSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "QueueSource(spec: {:?}, stream_state: {:?}, current: {:?}, queued: {}, \
+                crossfade: {} frames)",
+            self.spec,
+            self.stream_state,
+            self.current.as_ref().map(|(id, _)| id),
+            self.items.len(),
+            self.crossfade_frames,
+        ))
+    }
+}
+
+impl QueueSource {
+    pub fn new(spec: AudioSpec) -> Self {
+        Self {
+            spec,
+            stream_state: StreamState::Streaming,
+            crossfade_frames: 0,
+            wait_when_empty: false,
+            items: VecDeque::new(),
+            current: None,
+            lookahead: vec![0.0; spec.samplerate.get() as usize * spec.channels.get() as usize],
+            lookahead_frames: 0,
+            scratch: vec![0.0; spec.samplerate.get() as usize * spec.channels.get() as usize],
+            finished: Vec::new(),
+        }
+    }
+
+    /// Fade each item into the next over `crossfade`.
+    pub fn with_crossfade(self, crossfade: FadeLength) -> Self {
+        let crossfade_frames = crossfade.frames(self.spec.samplerate);
+        let crossfade_len = crossfade_frames * self.spec.channels.get() as usize;
+
+        Self {
+            crossfade_frames,
+            lookahead: vec![0.0; self.lookahead.len() + crossfade_len],
+            // the incoming side of a crossfade is rendered into scratch in one go
+            scratch: vec![0.0; self.scratch.len().max(crossfade_len)],
+            ..self
+        }
+    }
+
+    /// Keep playing silence when out of items, waiting for more to be appended, rather
+    /// than completing.
+    pub fn with_wait_when_empty(self, wait_when_empty: bool) -> Self {
+        Self {
+            wait_when_empty,
+            ..self
+        }
+    }
+
+    /// Add an item to the end of the queue.
+    pub fn append(&mut self, id: SourceId, item: QueueItem) -> Result<(), MismatchedSpecError> {
+        let source = item.into_source();
+
+        if source.spec() != self.spec {
+            return Err(MismatchedSpecError);
+        }
+
+        // make room ahead of time so that nothing is allocated while mixing
+        self.finished.reserve(self.items.len() + 2);
+        self.items.push_back((id, Box::new(source)));
+        Ok(())
+    }
+
+    /// Drop all items yet to be started. The item currently playing plays to its end.
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    /// Ids of the items finished since the previous call.
+    pub fn take_finished(&mut self) -> impl Iterator<Item = SourceId> + '_ {
+        self.finished.drain(..)
+    }
+
+    /// Start the next item, fading the end of the lookahead into it. Returns false if
+    /// the queue is empty.
+    fn start_next_item(&mut self) -> bool {
+        let Some((id, mut source)) = self.items.pop_front() else {
+            return false;
+        };
+
+        let chans = self.spec.channels.get() as usize;
+        let fade_frames = self.crossfade_frames.min(self.lookahead_frames);
+
+        if fade_frames > 0 {
+            let incoming = &mut self.scratch[..fade_frames * chans];
+            incoming.fill(0.0);
+            source.mix_to_same_spec(incoming);
+
+            let outgoing = &mut self.lookahead
+                [(self.lookahead_frames - fade_frames) * chans..self.lookahead_frames * chans];
+
+            for (n, (out_frame, in_frame)) in outgoing
+                .chunks_exact_mut(chans)
+                .zip(incoming.chunks_exact(chans))
+                .enumerate()
+            {
+                let level = (n + 1) as f32 / fade_frames as f32;
+
+                for (output, input) in out_frame.iter_mut().zip(in_frame.iter()) {
+                    *output = *output * (1.0 - level) + input * level;
+                }
+            }
+        }
+
+        self.current = Some((id, source));
+        true
+    }
+
+    /// Read ahead until the lookahead holds `frames` frames plus the length of the
+    /// crossfade, or the queue runs out.
+    fn read_ahead(&mut self, frames: usize) {
+        let chans = self.spec.channels.get() as usize;
+        let target_frames = self.crossfade_frames + frames;
+
+        if self.lookahead.len() < target_frames * chans {
+            // only when called with more than the expected one second at a time
+            self.lookahead.resize(target_frames * chans, 0.0);
+        }
+
+        loop {
+            let Some((id, source)) = &mut self.current else {
+                if self.start_next_item() {
+                    continue;
+                }
+
+                return;
+            };
+
+            if self.lookahead_frames >= target_frames {
+                return;
+            }
+
+            let wanted_frames =
+                (target_frames - self.lookahead_frames).min(self.scratch.len() / chans);
+            let destination = &mut self.lookahead
+                [self.lookahead_frames * chans..(self.lookahead_frames + wanted_frames) * chans];
+
+            destination.fill(0.0);
+
+            let frames_read = source.mix_to_same_spec(destination).get();
+            self.lookahead_frames += frames_read;
+
+            if source.stream_state() == StreamState::Complete {
+                self.finished.push(*id);
+                self.current = None;
+            } else if frames_read < wanted_frames {
+                return;
+            }
+        }
+    }
+}
+
+impl SourceOps for QueueSource {
+    fn spec(&self) -> AudioSpec {
+        self.spec
+    }
+
+    fn stream_state(&self) -> StreamState {
+        self.stream_state
+    }
+
+    fn stream_position(&self) -> Option<NumFrames> {
+        None
+    }
+
+    fn channel_layout(&self) -> Option<Channels> {
+        None
+    }
+
+    fn mix_to_same_spec(&mut self, out_buffer: &mut [f32]) -> NumFrames {
+        let chans = self.spec.channels.get() as usize;
+        let out_frames = out_buffer.len() / chans;
+        let mut frames_mixed = 0;
+
+        while frames_mixed < out_frames {
+            let frames_wanted = (out_frames - frames_mixed).min(self.scratch.len() / chans);
+
+            self.read_ahead(frames_wanted);
+
+            // the end of an item playing is held back for fading into the next item
+            let held_frames = if self.current.is_some() {
+                self.crossfade_frames
+            } else {
+                0
+            };
+
+            let frames = self
+                .lookahead_frames
+                .saturating_sub(held_frames)
+                .min(frames_wanted);
+
+            if frames == 0 {
+                break;
+            }
+
+            out_buffer[frames_mixed * chans..(frames_mixed + frames) * chans]
+                .iter_mut()
+                .zip(self.lookahead.iter())
+                .for_each(|(output, sample)| *output += sample);
+
+            self.lookahead
+                .copy_within(frames * chans..self.lookahead_frames * chans, 0);
+            self.lookahead_frames -= frames;
+            frames_mixed += frames;
+        }
+
+        if self.current.is_none()
+            && self.items.is_empty()
+            && self.lookahead_frames == 0
+            && !self.wait_when_empty
+        {
+            self.stream_state = StreamState::Complete;
+        }
+
+        NumFrames::new(frames_mixed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer_item(data: &[f32]) -> QueueItem {
+        let spec = AudioSpec::new(48000, 1).unwrap();
+        QueueItem::BufferSource(BufferSource::new(spec, data.into()).unwrap())
+    }
+
+    #[test]
+    fn test_gapless() {
+        let spec = AudioSpec::new(48000, 1).unwrap();
+        let mut queue = QueueSource::new(spec);
+        let (first, second) = (SourceId::unique(), SourceId::unique());
+
+        queue.append(first, buffer_item(&[1.0, 2.0, 3.0])).unwrap();
+        queue.append(second, buffer_item(&[4.0, 5.0])).unwrap();
+
+        let mut buf = [0.0; 4];
+
+        assert_eq!(queue.mix_to_same_spec(&mut buf), NumFrames::new(4));
+        assert_eq!(buf, [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(queue.take_finished().collect::<Vec<_>>(), [first]);
+        assert_eq!(queue.stream_state(), StreamState::Streaming);
+
+        let mut buf = [0.0; 4];
+
+        assert_eq!(queue.mix_to_same_spec(&mut buf), NumFrames::new(1));
+        assert_eq!(buf, [5.0, 0.0, 0.0, 0.0]);
+        assert_eq!(queue.take_finished().collect::<Vec<_>>(), [second]);
+        assert_eq!(queue.stream_state(), StreamState::Complete);
+
+        let stereo = AudioSpec::new(48000, 2).unwrap();
+        assert!(QueueSource::new(stereo)
+            .append(first, buffer_item(&[1.0]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_crossfade() {
+        let spec = AudioSpec::new(48000, 1).unwrap();
+        let mut queue =
+            QueueSource::new(spec).with_crossfade(FadeLength::Frames(NumFrames::new(2)));
+
+        queue
+            .append(SourceId::unique(), buffer_item(&[1.0; 4]))
+            .unwrap();
+        queue
+            .append(SourceId::unique(), buffer_item(&[3.0; 4]))
+            .unwrap();
+
+        let mut buf = [0.0; 8];
+
+        assert_eq!(queue.mix_to_same_spec(&mut buf), NumFrames::new(6));
+        assert_eq!(buf, [1.0, 1.0, 2.0, 3.0, 3.0, 3.0, 0.0, 0.0]);
+        assert_eq!(queue.stream_state(), StreamState::Complete);
+    }
+
+    #[test]
+    fn test_crossfade_longer_than_scratch() {
+        let spec = AudioSpec::new(48000, 1).unwrap();
+        let fade_frames = 60000;
+        let mut queue =
+            QueueSource::new(spec).with_crossfade(FadeLength::Frames(NumFrames::new(fade_frames)));
+
+        queue
+            .append(SourceId::unique(), buffer_item(&vec![1.0; 70000]))
+            .unwrap();
+        queue
+            .append(SourceId::unique(), buffer_item(&vec![3.0; 70000]))
+            .unwrap();
+
+        let mut buf = vec![0.0; 140000];
+
+        assert_eq!(queue.mix_to_same_spec(&mut buf), NumFrames::new(80000));
+        assert_eq!(buf[9999], 1.0);
+        assert_eq!(buf[10000 + fade_frames / 2 - 1], 2.0);
+        assert_eq!(buf[10000 + fade_frames - 1], 3.0);
+        assert_eq!(buf[79999], 3.0);
+        assert_eq!(queue.stream_state(), StreamState::Complete);
+    }
+
+    #[test]
+    fn test_wait_when_empty() {
+        let spec = AudioSpec::new(48000, 1).unwrap();
+        let mut queue = QueueSource::new(spec).with_wait_when_empty(true);
+        let mut buf = [0.0; 2];
+
+        assert_eq!(queue.mix_to_same_spec(&mut buf), NumFrames::new(0));
+        assert_eq!(queue.stream_state(), StreamState::Streaming);
+
+        queue
+            .append(SourceId::unique(), buffer_item(&[1.0, 2.0]))
+            .unwrap();
+
+        assert_eq!(queue.mix_to_same_spec(&mut buf), NumFrames::new(2));
+        assert_eq!(buf, [1.0, 2.0]);
+        assert_eq!(queue.stream_state(), StreamState::Streaming);
+    }
+}
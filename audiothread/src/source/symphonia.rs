@@ -5,6 +5,7 @@
 use std::{
     fs::File,
     io::{BufReader, Read, Seek},
+    path::Path,
 };
 
 use ringbuf::{
@@ -12,11 +13,13 @@ use ringbuf::{
     HeapRb,
 };
 use symphonia::core::{
-    audio::{AudioBufferRef as SymphoniaAudioBufferRef, SampleBuffer as SymphoniaSampleBuffer},
-    codecs::Decoder as SymphoniaDecoder,
-    formats::FormatReader as SymphoniaFormatReader,
+    audio::{AudioBufferRef, Channels, SampleBuffer as SymphoniaSampleBuffer},
+    codecs::{Decoder as SymphoniaDecoder, CODEC_TYPE_NULL},
+    errors::Error as SymphoniaErrorKind,
+    formats::{FormatReader as SymphoniaFormatReader, SeekMode, SeekTo},
     io::{MediaSource as SymphoniaMediaSource, MediaSourceStream as SymphoniaMediaSourceStream},
     probe::Hint as SymphoniaProbeHint,
+    units::{Time, TimeBase},
 };
 use thiserror::Error as ThisError;
 
@@ -24,29 +27,64 @@ use crate::{
     error::{error_enum, IOError, SymphoniaError, SymphoniaSourceError, ValueOutOfRangeError},
     ext::Frames,
     source::SourceOps,
-    types::{AudioSpec, StreamState},
+    types::{AudioSpec, NumFrames, StreamState},
 };
 
+/// Recoverable errors in a row tolerated before a stream is considered broken.
+const MAX_CONSECUTIVE_ERRORS: u32 = 100;
+
+/// How many times a [`SymphoniaSource`] plays its region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Looping {
+    Off,
+
+    /// Play the region this many times in total.
+    Times(u32),
+
+    Forever,
+}
+
+/// A source decoding a file or stream with symphonia.
+///
+/// Unlike other sources, mixing allocates, as symphonia allocates each packet it reads.
 pub struct SymphoniaSource {
     spec: AudioSpec,
     stream_state: StreamState,
     reader: Box<dyn SymphoniaFormatReader>,
     decoder: Box<dyn SymphoniaDecoder>,
     track_id: u32,
+    time_base: Option<TimeBase>,
     buffer: HeapRb<f32>,
+    sample_buf: Option<SymphoniaSampleBuffer<f32>>,
+    region_start: u64,
+    region_end: Option<u64>,
+    start_offset: Option<u64>,
+    looping: Looping,
+    times_played: u32,
+    started: bool,
+    decode_position: u64,
+    skip_frames: u64,
+    decoded_since_restart: bool,
+    decode_errors: u64,
 }
 
 impl std::fmt::Debug for SymphoniaSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&format!(
             "SymphoniaSource(spec: {:?}, stream_state: {:?}, codec: {:?}, track_id: {}, \
-                buffer: {} of {})",
+                buffer: {} of {}, region: {}..{:?}, looping: {:?}, position: {}, \
+                decode errors: {})",
             self.spec,
             self.stream_state,
             self.decoder.codec_params(),
             self.track_id,
             self.buffer.occupied_len(),
             self.buffer.capacity(),
+            self.region_start,
+            self.region_end,
+            self.looping,
+            self.decode_position,
+            self.decode_errors,
         ))
     }
 }
@@ -85,22 +123,109 @@ impl SymphoniaSource {
                 .count() as u8,
         )?;
 
+        let time_base = decoder.codec_params().time_base;
+
         Ok(Self {
             spec,
             stream_state: StreamState::Streaming,
             reader,
             decoder,
             track_id,
+            time_base,
             buffer: HeapRb::new(spec.channels.get() as usize * spec.samplerate.get() as usize),
+            sample_buf: None,
+            region_start: 0,
+            region_end: None,
+            start_offset: None,
+            looping: Looping::Off,
+            times_played: 0,
+            started: false,
+            decode_position: 0,
+            skip_frames: 0,
+            decoded_since_restart: false,
+            decode_errors: 0,
         })
     }
 
+    /// Play only the frames in `start..end`, or from `start` to the end of the stream if
+    /// `end` is `None`.
+    pub fn with_region(self, start: NumFrames, end: Option<NumFrames>) -> Self {
+        Self {
+            region_start: start.get() as u64,
+            region_end: end.map(|end| std::cmp::max(start, end).get() as u64),
+            ..self
+        }
+    }
+
+    /// Start playing at `offset` frames instead of at the start of the region. Looping
+    /// restarts at the start of the region.
+    pub fn with_start_offset(self, offset: NumFrames) -> Self {
+        Self {
+            start_offset: Some(offset.get() as u64),
+            ..self
+        }
+    }
+
+    pub fn with_looping(self, looping: Looping) -> Self {
+        Self { looping, ..self }
+    }
+
+    pub fn looping(&self) -> Looping {
+        self.looping
+    }
+
+    /// Open a file, playing its default track. The file extension, if any, helps in
+    /// detecting the format.
     pub fn from_file(path: &str) -> Result<SymphoniaSource, SymphoniaSourceImplError> {
-        Self::from_buf_reader(BufReader::new(File::open(path)?))
+        Self::open(
+            BufReader::new(File::open(path)?),
+            extension(path).as_deref(),
+            None,
+        )
+    }
+
+    /// Open a file, playing the track with the given id in a multi-track container.
+    pub fn from_file_with_track(
+        path: &str,
+        track_id: u32,
+    ) -> Result<SymphoniaSource, SymphoniaSourceImplError> {
+        Self::open(
+            BufReader::new(File::open(path)?),
+            extension(path).as_deref(),
+            Some(track_id),
+        )
     }
 
     pub fn from_buf_reader<R: Read + Seek + Send + Sync + 'static>(
+        bufreader: BufReader<R>,
+    ) -> Result<SymphoniaSource, SymphoniaSourceImplError> {
+        Self::open(bufreader, None, None)
+    }
+
+    /// Like [`SymphoniaSource::from_buf_reader`], with a file extension (e.g "mp3")
+    /// helping in detecting the format.
+    pub fn from_buf_reader_with_hint<R: Read + Seek + Send + Sync + 'static>(
+        bufreader: BufReader<R>,
+        extension: &str,
+    ) -> Result<SymphoniaSource, SymphoniaSourceImplError> {
+        Self::open(bufreader, Some(extension), None)
+    }
+
+    /// Like [`SymphoniaSource::from_buf_reader`], playing the track with the given id in a
+    /// multi-track container.
+    pub fn from_buf_reader_with_track<R: Read + Seek + Send + Sync + 'static>(
+        bufreader: BufReader<R>,
+        track_id: u32,
+    ) -> Result<SymphoniaSource, SymphoniaSourceImplError> {
+        Self::open(bufreader, None, Some(track_id))
+    }
+
+    /// Probe the format and set up decoding of the given track, or if `None` the default
+    /// track or else the first track with a known codec.
+    fn open<R: Read + Seek + Send + Sync + 'static>(
         mut bufreader: BufReader<R>,
+        extension: Option<&str>,
+        track_id: Option<u32>,
     ) -> Result<SymphoniaSource, SymphoniaSourceImplError> {
         let len = bufreader.seek(std::io::SeekFrom::End(0)).ok();
         let _ = bufreader.seek(std::io::SeekFrom::Start(0));
@@ -110,46 +235,210 @@ impl SymphoniaSource {
             Default::default(),
         );
 
-        match symphonia::default::get_probe().format(
-            &SymphoniaProbeHint::new(),
+        let mut hint = SymphoniaProbeHint::new();
+
+        if let Some(extension) = extension {
+            hint.with_extension(extension);
+        }
+
+        let probed = symphonia::default::get_probe().format(
+            &hint,
             mss,
             &Default::default(),
             &Default::default(),
-        ) {
-            Ok(probed) => {
-                let codecs = symphonia::default::get_codecs();
-                let track_id: u32 = probed
-                    .format
-                    .default_track()
-                    .ok_or(SymphoniaSourceError("No default track".to_string()))?
-                    .id;
-                let codec_params = &probed
-                    .format
-                    .default_track()
-                    .ok_or(SymphoniaSourceError("No default track".to_string()))?
-                    .codec_params;
-                let decoder = codecs.make(codec_params, &Default::default())?;
-
-                Ok(SymphoniaSource::new(probed.format, decoder, track_id)?)
+        )?;
+
+        let track = match track_id {
+            Some(id) => probed
+                .format
+                .tracks()
+                .iter()
+                .find(|track| track.id == id)
+                .ok_or(SymphoniaSourceError(format!("No track with id {id}")))?,
+            None => probed
+                .format
+                .default_track()
+                .filter(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+                .or_else(|| {
+                    probed
+                        .format
+                        .tracks()
+                        .iter()
+                        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+                })
+                .ok_or(SymphoniaSourceError("No playable track".to_string()))?,
+        };
+
+        let track_id = track.id;
+        let decoder =
+            symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?;
+
+        SymphoniaSource::new(probed.format, decoder, track_id)
+    }
+
+    /// Number of corrupt packets skipped so far.
+    pub fn decode_errors(&self) -> u64 {
+        self.decode_errors
+    }
+
+    fn frames_to_timestamp(&self, frames: u64) -> u64 {
+        let samplerate = self.spec.samplerate.get() as u64;
+
+        match self.time_base {
+            Some(time_base) => time_base.calc_timestamp(Time::new(
+                frames / samplerate,
+                (frames % samplerate) as f64 / samplerate as f64,
+            )),
+            None => frames,
+        }
+    }
+
+    fn timestamp_to_frames(&self, ts: u64) -> u64 {
+        let samplerate = self.spec.samplerate.get() as u64;
+
+        match self.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(ts);
+                time.seconds * samplerate + (time.frac * samplerate as f64).round() as u64
             }
-            Err(e) => Err(e.into()),
+            None => ts,
         }
     }
 
-    fn decode_next_packet(&mut self) -> Option<SymphoniaAudioBufferRef> {
-        let mut packet = self.reader.next_packet().ok()?;
+    /// Move decoding to `frame`, seeking through the format reader where supported and
+    /// otherwise decoding and discarding frames up to it. Returns false if `frame` could
+    /// not be reached.
+    fn seek_to_frame(&mut self, frame: u64) -> bool {
+        if frame == self.decode_position && self.skip_frames == 0 {
+            return true;
+        }
+
+        let seeked = self.reader.seek(
+            SeekMode::Accurate,
+            SeekTo::TimeStamp {
+                ts: self.frames_to_timestamp(frame),
+                track_id: self.track_id,
+            },
+        );
 
-        while packet.track_id() != self.track_id {
-            packet = self.reader.next_packet().ok()?;
+        match seeked {
+            Ok(seeked_to) => {
+                self.decoder.reset();
+                self.skip_frames =
+                    frame.saturating_sub(self.timestamp_to_frames(seeked_to.actual_ts));
+                self.decode_position = frame;
+                true
+            }
+
+            Err(e) if frame >= self.decode_position + self.skip_frames => {
+                log::log!(
+                    log::Level::Debug,
+                    "Seek failed ({e}), skipping ahead instead"
+                );
+                self.skip_frames = frame - self.decode_position;
+                self.decode_position = frame;
+                true
+            }
+
+            Err(e) => {
+                log::log!(log::Level::Warn, "Unable to seek backwards: {e}");
+                false
+            }
         }
+    }
+
+    /// Restart the region if looping allows, returning false if playback should end.
+    fn restart_region(&mut self) -> bool {
+        self.times_played += 1;
+
+        let again = match self.looping {
+            Looping::Off => false,
+            Looping::Times(n) => self.times_played < n,
+            Looping::Forever => true,
+        };
 
-        match self.decoder.decode(&packet) {
-            Ok(audiobuf) => Some(audiobuf),
-            Err(_) => None,
+        // guard against spinning on regions that produce no audio
+        if !again || !std::mem::replace(&mut self.decoded_since_restart, false) {
+            return false;
+        }
+
+        self.seek_to_frame(self.region_start)
+    }
+
+    /// Decode the next packet of the track into `sample_buf`, skipping corrupt packets.
+    /// Returns false at the end of the stream or on an unrecoverable error.
+    fn decode_next_packet(&mut self) -> bool {
+        let mut consecutive_errors = 0;
+
+        loop {
+            let result = match self.reader.next_packet() {
+                Ok(packet) if packet.track_id() != self.track_id => continue,
+                Ok(packet) => self
+                    .decoder
+                    .decode(&packet)
+                    .map(|audiobuf| store_samples(&mut self.sample_buf, audiobuf)),
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(()) => return true,
+
+                Err(SymphoniaErrorKind::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return false;
+                }
+
+                Err(SymphoniaErrorKind::DecodeError(e)) => {
+                    log::log!(log::Level::Debug, "Skipping corrupt packet: {e}");
+                    self.decode_errors += 1;
+                }
+
+                Err(SymphoniaErrorKind::ResetRequired) => self.decoder.reset(),
+
+                Err(e) => {
+                    log::log!(log::Level::Warn, "Decoding failed: {e}");
+                    return false;
+                }
+            }
+
+            consecutive_errors += 1;
+
+            if consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
+                log::log!(
+                    log::Level::Warn,
+                    "Giving up decoding after {consecutive_errors} errors in a row"
+                );
+
+                return false;
+            }
         }
     }
 }
 
+/// Copy a decoded packet into `sample_buf`, which is only reallocated when the packet
+/// doesn't fit.
+fn store_samples(sample_buf: &mut Option<SymphoniaSampleBuffer<f32>>, audiobuf: AudioBufferRef) {
+    let required_samples = audiobuf.capacity() * audiobuf.spec().channels.count();
+
+    let sample_buf = match sample_buf {
+        Some(buf) if buf.capacity() >= required_samples => buf,
+        _ => sample_buf.insert(SymphoniaSampleBuffer::new(
+            audiobuf.capacity() as u64,
+            *audiobuf.spec(),
+        )),
+    };
+
+    sample_buf.copy_interleaved_ref(audiobuf);
+}
+
+fn extension(path: &str) -> Option<String> {
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_string)
+}
+
 impl SourceOps for SymphoniaSource {
     fn spec(&self) -> AudioSpec {
         self.spec
@@ -159,11 +448,27 @@ impl SourceOps for SymphoniaSource {
         self.stream_state
     }
 
-    fn mix_to_same_spec(&mut self, out_buffer: &mut [f32]) {
+    fn channel_layout(&self) -> Option<Channels> {
+        self.decoder.codec_params().channels
+    }
+
+    fn decode_errors(&self) -> u64 {
+        self.decode_errors
+    }
+
+    fn stream_position(&self) -> Option<NumFrames> {
+        let buffered_frames = self.buffer.occupied_len() / self.spec.channels.get() as usize;
+
+        Some(NumFrames::new(
+            self.decode_position as usize - buffered_frames,
+        ))
+    }
+
+    fn mix_to_same_spec(&mut self, out_buffer: &mut [f32]) -> NumFrames {
         let self_chans = self.spec.channels.get() as usize;
         let num_out_buffer_frames = out_buffer.len_frames(self.spec).get();
 
-        debug_assert!(self.buffer.occupied_len() % self_chans == 0);
+        debug_assert!(self.buffer.occupied_len().is_multiple_of(self_chans));
 
         let prior_decoded_frames_avail = self.buffer.occupied_len() / self_chans;
         let prior_decoded_frames_drained =
@@ -177,43 +482,81 @@ impl SourceOps for SymphoniaSource {
         let mut out_buffer_frame_offset = prior_decoded_frames_drained;
         let mut frames_needed = num_out_buffer_frames - prior_decoded_frames_drained;
 
-        while frames_needed > 0 {
-            match self.decode_next_packet() {
-                Some(audiobuf) => {
-                    let mut samplebuf = SymphoniaSampleBuffer::<f32>::new(
-                        audiobuf.capacity() as u64,
-                        *audiobuf.spec(),
-                    );
-                    samplebuf.copy_interleaved_ref(audiobuf);
-
-                    debug_assert!(samplebuf.len() % self_chans == 0);
-
-                    let num_decoded_frames = samplebuf.len() / self_chans;
-                    let num_decoded_frames_mixed = std::cmp::min(frames_needed, num_decoded_frames);
-
-                    out_buffer
-                        .slice_frames_mut(self.spec, out_buffer_frame_offset..)
-                        .iter_mut()
-                        .zip(samplebuf.samples().iter())
-                        .for_each(|(output, sample)| *output += sample);
-
-                    if num_decoded_frames - num_decoded_frames_mixed > 0 {
-                        self.buffer.push_slice(
-                            samplebuf
-                                .samples()
-                                .slice_frames(self.spec, num_decoded_frames_mixed..),
-                        );
-                    }
-
-                    out_buffer_frame_offset += num_decoded_frames_mixed;
-                    frames_needed -= num_decoded_frames_mixed;
+        if !self.started && frames_needed > 0 {
+            self.started = true;
+
+            let start = std::cmp::max(self.start_offset.unwrap_or(0), self.region_start);
+
+            if !self.seek_to_frame(start) {
+                self.stream_state = StreamState::Complete;
+            }
+        }
+
+        while frames_needed > 0 && self.stream_state == StreamState::Streaming {
+            if self
+                .region_end
+                .is_some_and(|end| self.decode_position >= end)
+            {
+                if !self.restart_region() {
+                    self.stream_state = StreamState::Complete;
                 }
-                None => {
+
+                continue;
+            }
+
+            if !self.decode_next_packet() {
+                if !self.restart_region() {
                     self.stream_state = StreamState::Complete;
-                    break;
+                }
+
+                continue;
+            }
+
+            let samplebuf = self
+                .sample_buf
+                .as_ref()
+                .expect("A decoded packet should leave samples in the sample buffer");
+
+            debug_assert!(samplebuf.len().is_multiple_of(self_chans));
+
+            let num_skipped_frames =
+                std::cmp::min(self.skip_frames, (samplebuf.len() / self_chans) as u64);
+            self.skip_frames -= num_skipped_frames;
+
+            let mut samples = samplebuf
+                .samples()
+                .slice_frames(self.spec, num_skipped_frames as usize..);
+
+            if let Some(end) = self.region_end {
+                let frames_left_in_region = (end - self.decode_position) as usize;
+
+                if samples.len_frames(self.spec).get() > frames_left_in_region {
+                    samples = samples.slice_frames(self.spec, ..frames_left_in_region);
                 }
             }
+
+            let num_decoded_frames = samples.len_frames(self.spec).get();
+            let num_decoded_frames_mixed = std::cmp::min(frames_needed, num_decoded_frames);
+
+            self.decode_position += num_decoded_frames as u64;
+            self.decoded_since_restart |= num_decoded_frames > 0;
+
+            out_buffer
+                .slice_frames_mut(self.spec, out_buffer_frame_offset..)
+                .iter_mut()
+                .zip(samples.iter())
+                .for_each(|(output, sample)| *output += sample);
+
+            if num_decoded_frames - num_decoded_frames_mixed > 0 {
+                self.buffer
+                    .push_slice(samples.slice_frames(self.spec, num_decoded_frames_mixed..));
+            }
+
+            out_buffer_frame_offset += num_decoded_frames_mixed;
+            frames_needed -= num_decoded_frames_mixed;
         }
+
+        NumFrames::new(out_buffer_frame_offset)
     }
 }
 
@@ -262,4 +605,156 @@ mod tests {
 
         assert_eq!(sf.stream_state(), StreamState::Complete);
     }
+
+    fn square_wave() -> SymphoniaSource {
+        SymphoniaSource::from_file(&format!(
+            "{}/test_assets/square_1ch_48k_20smp.wav",
+            std::env::var("CARGO_MANIFEST_DIR").unwrap()
+        ))
+        .unwrap()
+    }
+
+    /// Round samples of the (16-bit) square wave to -1, 0 or 1.
+    fn rounded(buf: &[f32]) -> Vec<i32> {
+        buf.iter().map(|x| x.round() as i32).collect()
+    }
+
+    #[test]
+    fn test_symphoniafile_region_and_offset() {
+        let mut sf = square_wave().with_region(NumFrames::new(5), Some(NumFrames::new(15)));
+        let mut buf = [0.0f32; 12];
+
+        assert_eq!(sf.mix_to_same_spec(&mut buf), NumFrames::new(10));
+        assert_eq!(rounded(&buf), [1, 1, 1, 1, 1, -1, -1, -1, -1, -1, 0, 0]);
+        assert_eq!(sf.stream_state(), StreamState::Complete);
+
+        let mut sf = square_wave().with_start_offset(NumFrames::new(18));
+        let mut buf = [0.0f32; 4];
+
+        assert_eq!(sf.mix_to_same_spec(&mut buf), NumFrames::new(2));
+        assert_eq!(rounded(&buf), [-1, -1, 0, 0]);
+    }
+
+    #[test]
+    fn test_symphoniafile_looping() {
+        let mut sf = square_wave()
+            .with_region(NumFrames::new(8), Some(NumFrames::new(12)))
+            .with_start_offset(NumFrames::new(10))
+            .with_looping(Looping::Times(3));
+
+        let mut buf = [0.0f32; 12];
+
+        assert_eq!(sf.mix_to_same_spec(&mut buf), NumFrames::new(10));
+        assert_eq!(rounded(&buf), [-1, -1, 1, 1, -1, -1, 1, 1, -1, -1, 0, 0]);
+        assert_eq!(sf.stream_state(), StreamState::Complete);
+
+        let mut sf = square_wave()
+            .with_region(NumFrames::new(15), None)
+            .with_looping(Looping::Forever);
+
+        let mut buf = [0.0f32; 100];
+
+        assert_eq!(sf.mix_to_same_spec(&mut buf), NumFrames::new(100));
+        assert!(rounded(&buf).iter().all(|x| *x == -1));
+        assert_eq!(sf.stream_state(), StreamState::Streaming);
+    }
+
+    /// Decoder failing on the second packet as if it were corrupt.
+    struct CorruptSecondPacket {
+        decoder: Box<dyn SymphoniaDecoder>,
+        packets: usize,
+    }
+
+    impl SymphoniaDecoder for CorruptSecondPacket {
+        fn try_new(
+            _params: &symphonia::core::codecs::CodecParameters,
+            _options: &symphonia::core::codecs::DecoderOptions,
+        ) -> symphonia::core::errors::Result<Self> {
+            Err(SymphoniaErrorKind::Unsupported("test decoder"))
+        }
+
+        fn supported_codecs() -> &'static [symphonia::core::codecs::CodecDescriptor] {
+            &[]
+        }
+
+        fn reset(&mut self) {
+            self.decoder.reset()
+        }
+
+        fn codec_params(&self) -> &symphonia::core::codecs::CodecParameters {
+            self.decoder.codec_params()
+        }
+
+        fn decode(
+            &mut self,
+            packet: &symphonia::core::formats::Packet,
+        ) -> symphonia::core::errors::Result<AudioBufferRef<'_>> {
+            self.packets += 1;
+
+            if self.packets == 2 {
+                Err(SymphoniaErrorKind::DecodeError("test corruption"))
+            } else {
+                self.decoder.decode(packet)
+            }
+        }
+
+        fn finalize(&mut self) -> symphonia::core::codecs::FinalizeResult {
+            self.decoder.finalize()
+        }
+
+        fn last_decoded(&self) -> AudioBufferRef<'_> {
+            self.decoder.last_decoded()
+        }
+    }
+
+    #[test]
+    fn test_symphoniafile_skips_corrupt_packets() {
+        let path = format!(
+            "{}/test_assets/silence_2ch_44.1k_88200smp.wav",
+            std::env::var("CARGO_MANIFEST_DIR").unwrap()
+        );
+
+        let sf = SymphoniaSource::from_file(&path).unwrap();
+        let track_id = sf.track_id;
+
+        let mut sf = SymphoniaSource::new(
+            sf.reader,
+            Box::new(CorruptSecondPacket {
+                decoder: sf.decoder,
+                packets: 0,
+            }),
+            track_id,
+        )
+        .unwrap();
+
+        let mut buf = vec![0.0f32; 88200];
+        let frames = sf.mix_to_same_spec(&mut buf).get();
+
+        // playback goes on past the corrupt packet, which is left out
+        assert!(frames > 22050 && frames < 44100);
+        assert_eq!(sf.decode_errors(), 1);
+        assert_eq!(sf.stream_state(), StreamState::Complete);
+    }
+
+    #[test]
+    fn test_symphoniafile_track_selection() {
+        let path = format!(
+            "{}/test_assets/square_1ch_48k_20smp.wav",
+            std::env::var("CARGO_MANIFEST_DIR").unwrap()
+        );
+
+        let track_id = square_wave().track_id;
+        let mut sf = SymphoniaSource::from_file_with_track(&path, track_id).unwrap();
+        let mut buf = [0.0f32; 20];
+
+        assert_eq!(sf.mix_to_same_spec(&mut buf), NumFrames::new(20));
+        assert!(SymphoniaSource::from_file_with_track(&path, track_id + 1).is_err());
+
+        let reader = || BufReader::new(File::open(&path).unwrap());
+        let mut sf = SymphoniaSource::from_buf_reader_with_track(reader(), track_id).unwrap();
+        let mut buf = [0.0f32; 20];
+
+        assert_eq!(sf.mix_to_same_spec(&mut buf), NumFrames::new(20));
+        assert!(SymphoniaSource::from_buf_reader_with_track(reader(), track_id + 1).is_err());
+    }
 }
@@ -0,0 +1,699 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::time::Duration;
+
+use crate::{
+    bus::DEFAULT_BUS,
+    error::ResamplerError,
+    ext::Frames,
+    meter::{LevelMeter, Levels},
+    resampler::Resampler,
+    source::{
+        channels::{
+            default_layout, make_channel_conversion, make_discrete_conversion, ChannelConversion,
+        },
+        Source, SourceOps,
+    },
+    types::{
+        AudioSpec, FadeLength, NumChannels, NumFrames, PlaybackRate, Quality, SourceId, StreamState,
+    },
+};
+
+/// Buffers used while mixing a voice, shared by the voices of a source group so that
+/// mixing doesn't allocate.
+pub(crate) struct VoiceScratch {
+    source: Vec<f32>,
+    converted: Vec<f32>,
+}
+
+impl VoiceScratch {
+    /// Scratch space for mixing up to `max_frames` frames at a time.
+    pub fn new(source_spec: AudioSpec, output_channels: NumChannels, max_frames: usize) -> Self {
+        Self {
+            source: vec![0.0; max_frames * source_spec.channels.get() as usize],
+            converted: vec![0.0; max_frames * output_channels.get() as usize],
+        }
+    }
+}
+
+/// Resamples a source to play it at a different rate.
+struct Varispeed {
+    resampler: Resampler,
+    channels: usize,
+
+    /// Source frames read but not yet consumed by the resampler.
+    input: Vec<f32>,
+    pending_frames: usize,
+}
+
+impl Varispeed {
+    fn new(quality: Quality, spec: AudioSpec, rate: f64) -> Result<Self, ResamplerError> {
+        let mut resampler =
+            Resampler::new(quality, spec.samplerate, spec.samplerate, spec.channels)?;
+        resampler.set_ratio(1.0 / rate);
+
+        Ok(Self {
+            resampler,
+            channels: spec.channels.get() as usize,
+            input: vec![0.0; spec.samplerate.get() as usize * spec.channels.get() as usize],
+            pending_frames: 0,
+        })
+    }
+
+    fn set_rate(&mut self, rate: f64) {
+        self.resampler.set_ratio(1.0 / rate);
+    }
+
+    /// Fill `output` with the source played at the current rate, returning the number of
+    /// frames read from the source. Frames the source can't provide are read as silence.
+    fn mix(&mut self, source: &mut Source, output: &mut [f32]) -> NumFrames {
+        let chans = self.channels;
+        let capacity_frames = self.input.len() / chans;
+        let output_frames = output.len() / chans;
+        let rate = 1.0 / self.resampler.ratio();
+        let mut frames_produced = 0;
+        let mut frames_read = 0;
+
+        while frames_produced < output_frames {
+            let frames_wanted = (((output_frames - frames_produced) as f64 * rate).ceil() as usize)
+                .clamp(1, capacity_frames);
+
+            if self.pending_frames < frames_wanted {
+                let fresh = &mut self.input[self.pending_frames * chans..frames_wanted * chans];
+
+                fresh.fill(0.0);
+                frames_read += source.mix_to_same_spec(fresh).get();
+                self.pending_frames = frames_wanted;
+            }
+
+            let (used, generated) = match self.resampler.process(
+                &self.input[..self.pending_frames * chans],
+                &mut output[frames_produced * chans..],
+            ) {
+                Ok((used, generated)) => (used.get(), generated.get()),
+                Err(e) => {
+                    log::log!(log::Level::Error, "Varispeed resampling failed: {e}");
+                    break;
+                }
+            };
+
+            self.input
+                .copy_within(used * chans..self.pending_frames * chans, 0);
+            self.pending_frames -= used;
+            frames_produced += generated;
+
+            if used == 0 && generated == 0 {
+                break;
+            }
+        }
+
+        NumFrames::new(frames_read)
+    }
+}
+
+/// Per-source playback options given when a source is started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayOpts {
+    gain: f32,
+    pan: f32,
+    fade_in: Option<FadeLength>,
+    start_time: Option<NumFrames>,
+    bus: String,
+    rate: PlaybackRate,
+    tag: Option<String>,
+}
+
+impl Default for PlayOpts {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            pan: 0.0,
+            fade_in: None,
+            start_time: None,
+            bus: DEFAULT_BUS.to_string(),
+            rate: PlaybackRate::Ratio(1.0),
+            tag: None,
+        }
+    }
+}
+
+impl PlayOpts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the linear gain of the source.
+    pub fn with_gain(self, gain: f32) -> Self {
+        Self {
+            gain: gain.max(0.0),
+            ..self
+        }
+    }
+
+    /// Set the stereo pan of the source, from -1.0 (left) to 1.0 (right). Only the front
+    /// left and right output channels are panned, any others play at the source's gain.
+    pub fn with_pan(self, pan: f32) -> Self {
+        Self {
+            pan: pan.clamp(-1.0, 1.0),
+            ..self
+        }
+    }
+
+    /// Fade the source in from silence when it starts playing.
+    pub fn with_fade_in(self, fade_in: FadeLength) -> Self {
+        Self {
+            fade_in: Some(fade_in),
+            ..self
+        }
+    }
+
+    /// Start the source at the given output stream time, in output frames (see
+    /// [`crate::Message::GetStreamTime`]). A time already passed starts the source
+    /// right away.
+    pub fn with_start_time(self, start_time: NumFrames) -> Self {
+        Self {
+            start_time: Some(start_time),
+            ..self
+        }
+    }
+
+    /// Play the source on the named bus, created if needed, rather than on
+    /// [`DEFAULT_BUS`].
+    pub fn with_bus(self, bus: impl Into<String>) -> Self {
+        Self {
+            bus: bus.into(),
+            ..self
+        }
+    }
+
+    /// Play the source faster or slower, raising or lowering its pitch.
+    pub fn with_rate(self, rate: PlaybackRate) -> Self {
+        Self { rate, ..self }
+    }
+
+    /// Tag the source, e.g with the part of the application that started it, for
+    /// matching with [`crate::SourceMatcher::match_tag`].
+    pub fn with_tag(self, tag: impl Into<String>) -> Self {
+        Self {
+            tag: Some(tag.into()),
+            ..self
+        }
+    }
+}
+
+/// Snapshot of the state of a single source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceInfo {
+    pub id: SourceId,
+    pub spec: AudioSpec,
+    pub stream_state: StreamState,
+    pub paused: bool,
+
+    /// Number of frames played so far, in the source's own spec.
+    pub position: NumFrames,
+
+    /// Position of the next frame to be mixed within the source's file or buffer, if it
+    /// has one. Unlike `position` this follows seeks and loops.
+    pub stream_position: Option<NumFrames>,
+
+    /// The stream position currently being heard, i.e. `stream_position` compensated for
+    /// output latency, if the backend reports its latency.
+    pub audible_position: Option<NumFrames>,
+
+    pub gain: f32,
+    pub pan: f32,
+    pub bus: String,
+    pub tag: Option<String>,
+
+    /// Playback rate as a ratio, 1.0 being normal speed.
+    pub rate: f64,
+
+    /// Number of corrupt parts of the stream skipped while decoding.
+    pub decode_errors: u64,
+}
+
+/// A source being played by the audio thread, along with its playback state.
+pub(crate) struct Voice {
+    id: SourceId,
+    source: Source,
+    paused: bool,
+    position: NumFrames,
+    gain: f32,
+    pan: f32,
+    applied_channel_gains: (f32, f32),
+
+    /// Gain most recently applied to the output channels beyond front left and right.
+    applied_gain: f32,
+
+    upmixed_mono: bool,
+    fade: Fade,
+    stopping: bool,
+    meter: LevelMeter,
+
+    /// Peak output level of the most recent buffer mixed, once any has been.
+    recent_peak: Option<f32>,
+
+    output_channels: NumChannels,
+    channel_conv: Option<ChannelConversion>,
+    start_time: Option<NumFrames>,
+    play_time: NumFrames,
+    bus: String,
+    tag: Option<String>,
+    rate: f64,
+    conversion_quality: Quality,
+
+    /// Created once the voice is first played at a rate other than 1.0.
+    varispeed: Option<Varispeed>,
+}
+
+impl std::fmt::Debug for Voice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "Voice(id: {:?}, source: {:?}, paused: {}, position: {:?}, gain: {}, pan: {})",
+            self.id, self.source, self.paused, self.position, self.gain, self.pan,
+        ))
+    }
+}
+
+impl Voice {
+    pub fn new(
+        id: SourceId,
+        source: Source,
+        opts: PlayOpts,
+        output_channels: NumChannels,
+        conversion_quality: Quality,
+        stream_time: NumFrames,
+    ) -> Self {
+        let source_channels = source.spec().channels;
+
+        let source_layout = source
+            .channel_layout()
+            .filter(|layout| layout.count() == source_channels.get() as usize)
+            .or_else(|| default_layout(source_channels));
+
+        // channels beyond the known speaker positions are mapped by index
+        let channel_conv = match (source_layout, default_layout(output_channels)) {
+            (Some(source_layout), Some(output_layout)) => {
+                make_channel_conversion(source_layout, output_layout)
+            }
+            _ => make_discrete_conversion(source_channels, output_channels),
+        };
+        let upmixed_mono = source_channels.get() == 1 && output_channels.get() > 1;
+        let channel_gains = channel_gains(opts.gain, opts.pan, upmixed_mono);
+
+        let fade = match opts.fade_in {
+            Some(length) => Fade::new(0.0, 1.0, length.frames(source.spec().samplerate)),
+            None => Fade::constant(1.0),
+        };
+
+        let mut voice = Self {
+            id,
+            source,
+            paused: false,
+            position: NumFrames::new(0),
+            gain: opts.gain,
+            pan: opts.pan,
+            applied_channel_gains: channel_gains,
+            applied_gain: opts.gain,
+            upmixed_mono,
+            fade,
+            stopping: false,
+            meter: LevelMeter::default(),
+            recent_peak: None,
+            output_channels,
+            channel_conv,
+            start_time: opts.start_time,
+            play_time: opts
+                .start_time
+                .map_or(stream_time, |time| time.max(stream_time)),
+            bus: opts.bus,
+            tag: opts.tag,
+            rate: 1.0,
+            conversion_quality,
+            varispeed: None,
+        };
+
+        voice.set_rate(opts.rate);
+        voice
+    }
+
+    pub fn id(&self) -> SourceId {
+        self.id
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    pub fn source_mut(&mut self) -> &mut Source {
+        &mut self.source
+    }
+
+    /// Output stream time at which the voice is to start, until it has started.
+    pub fn start_time(&self) -> Option<NumFrames> {
+        self.start_time
+    }
+
+    /// Output stream time at which the voice started, or is scheduled to start.
+    pub fn play_time(&self) -> NumFrames {
+        self.play_time
+    }
+
+    pub fn set_started(&mut self) {
+        self.start_time = None;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain.max(0.0);
+    }
+
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = pan.clamp(-1.0, 1.0);
+    }
+
+    pub fn set_rate(&mut self, rate: PlaybackRate) {
+        let rate = rate.ratio();
+
+        match &mut self.varispeed {
+            Some(varispeed) => varispeed.set_rate(rate),
+            None if rate == 1.0 => (),
+            None => match Varispeed::new(self.conversion_quality, self.source.spec(), rate) {
+                Ok(varispeed) => self.varispeed = Some(varispeed),
+                Err(e) => {
+                    log::log!(log::Level::Error, "Failed to change playback rate: {e}");
+                    return;
+                }
+            },
+        }
+
+        self.rate = rate;
+    }
+
+    /// Fade the voice out over `frames` frames, after which it is done playing.
+    pub fn fade_out(&mut self, frames: usize) {
+        self.stopping = true;
+
+        self.fade = if self.paused {
+            Fade::constant(0.0)
+        } else {
+            Fade::new(self.fade.level, 0.0, frames)
+        };
+    }
+
+    /// Whether the voice has played to completion or finished fading out.
+    pub fn is_done(&self) -> bool {
+        self.source.stream_state() == StreamState::Complete
+            || (self.stopping && self.fade.is_complete())
+    }
+
+    /// Whether the voice is yet to finish playing and isn't already fading out.
+    pub fn is_live(&self) -> bool {
+        !self.stopping && !self.is_done()
+    }
+
+    /// Peak output level of the most recent buffer mixed, `None` for voices not yet
+    /// heard.
+    pub fn loudness(&self) -> Option<f32> {
+        self.recent_peak
+    }
+
+    /// Output levels since the previous call.
+    pub fn take_levels(&mut self) -> Levels {
+        self.meter.take()
+    }
+
+    pub fn info(&self, output_latency: Option<Duration>) -> SourceInfo {
+        let stream_position = self.source.stream_position();
+
+        let audible_position = stream_position.zip(output_latency).map(|(pos, latency)| {
+            let latency_frames = (latency.as_secs_f64()
+                * self.source.spec().samplerate.get() as f64)
+                .round() as usize;
+
+            NumFrames::new(pos.get().saturating_sub(latency_frames))
+        });
+
+        SourceInfo {
+            id: self.id,
+            spec: self.source.spec(),
+            stream_state: self.source.stream_state(),
+            paused: self.paused,
+            position: self.position,
+            stream_position,
+            audible_position,
+            gain: self.gain,
+            pan: self.pan,
+            bus: self.bus.clone(),
+            tag: self.tag.clone(),
+            rate: self.rate,
+            decode_errors: self.source.decode_errors(),
+        }
+    }
+
+    pub fn bus(&self) -> &str {
+        &self.bus
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    pub fn spec(&self) -> AudioSpec {
+        self.source.spec()
+    }
+
+    /// Mix into a buffer at the source's sample rate and the output channel count,
+    /// applying gain and pan. Does nothing while paused.
+    pub fn mix_to_output_channels(&mut self, out_buffer: &mut [f32], scratch: &mut VoiceScratch) {
+        if self.paused || self.is_done() {
+            return;
+        }
+
+        let source_spec = self.source.spec();
+        let out_spec = AudioSpec {
+            samplerate: source_spec.samplerate,
+            channels: self.output_channels,
+        };
+
+        let num_frames = out_buffer.len_frames(out_spec).get();
+        let num_source_samples = num_frames * source_spec.channels.get() as usize;
+
+        let source_buf = &mut scratch.source[..num_source_samples];
+        source_buf.fill(0.0);
+
+        let frames_mixed = match &mut self.varispeed {
+            Some(varispeed) => varispeed.mix(&mut self.source, source_buf),
+            None => self.source.mix_to_same_spec(source_buf),
+        };
+
+        self.position = NumFrames::new(self.position.get() + frames_mixed.get());
+
+        let voice_buf = match &self.channel_conv {
+            Some(conv) => {
+                let converted_buf = &mut scratch.converted[..out_buffer.len()];
+                conv.convert(source_buf, converted_buf);
+                converted_buf
+            }
+            None => source_buf,
+        };
+
+        let (from_left, from_right) = self.applied_channel_gains;
+        let (to_left, to_right) = channel_gains(self.gain, self.pan, self.upmixed_mono);
+        let out_chans = self.output_channels.get() as usize;
+        let mut peak = 0.0f32;
+
+        for (n, (out_frame, voice_frame)) in out_buffer
+            .chunks_exact_mut(out_chans)
+            .zip(voice_buf.chunks_exact(out_chans))
+            .enumerate()
+        {
+            let t = (n + 1) as f32 / num_frames as f32;
+            let level = self.fade.advance();
+            let left = (from_left + (to_left - from_left) * t) * level;
+            let right = (from_right + (to_right - from_right) * t) * level;
+            let gain = (self.applied_gain + (self.gain - self.applied_gain) * t) * level;
+
+            if out_chans == 1 {
+                let sample = voice_frame[0] * left.max(right);
+                out_frame[0] += sample;
+                self.meter.add(sample);
+                peak = peak.max(sample.abs());
+            } else {
+                let (left_sample, right_sample) = (voice_frame[0] * left, voice_frame[1] * right);
+
+                out_frame[0] += left_sample;
+                out_frame[1] += right_sample;
+                self.meter.add(left_sample);
+                self.meter.add(right_sample);
+                peak = peak.max(left_sample.abs()).max(right_sample.abs());
+
+                for (output, sample) in out_frame[2..].iter_mut().zip(voice_frame[2..].iter()) {
+                    *output += sample * gain;
+                    self.meter.add(sample * gain);
+                    peak = peak.max((sample * gain).abs());
+                }
+            }
+        }
+
+        self.applied_channel_gains = (to_left, to_right);
+        self.applied_gain = self.gain;
+        self.recent_peak = Some(peak);
+    }
+}
+
+/// Linear gain envelope used for fading voices in and out.
+#[derive(Debug, Clone, Copy)]
+struct Fade {
+    level: f32,
+    target: f32,
+    step: f32,
+}
+
+impl Fade {
+    fn new(from: f32, to: f32, frames: usize) -> Self {
+        if frames == 0 {
+            Self::constant(to)
+        } else {
+            Self {
+                level: from,
+                target: to,
+                step: (to - from) / frames as f32,
+            }
+        }
+    }
+
+    fn constant(level: f32) -> Self {
+        Self {
+            level,
+            target: level,
+            step: 0.0,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.level == self.target
+    }
+
+    /// Advance the envelope by one frame, returning the new level.
+    fn advance(&mut self) -> f32 {
+        if !self.is_complete() {
+            self.level += self.step;
+
+            if (self.step > 0.0 && self.level >= self.target)
+                || (self.step <= 0.0 && self.level <= self.target)
+            {
+                self.level = self.target;
+            }
+        }
+
+        self.level
+    }
+}
+
+/// Left and right channel gains for the given gain and pan.
+///
+/// Pan attenuates the opposite side along an equal-power curve, keeping unity gain on
+/// both sides when centered. Mono sources, which are upmixed at -3 dB per side, are
+/// instead panned with the full equal-power law so that hard panning gives unity gain.
+fn channel_gains(gain: f32, pan: f32, upmixed_mono: bool) -> (f32, f32) {
+    if pan == 0.0 {
+        return (gain, gain);
+    }
+
+    let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+    let left = std::f32::consts::SQRT_2 * angle.cos();
+    let right = std::f32::consts::SQRT_2 * angle.sin();
+
+    if upmixed_mono {
+        (gain * left, gain * right)
+    } else if pan > 0.0 {
+        (gain * left, gain)
+    } else {
+        (gain, gain * right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::source::buffer::BufferSource;
+
+    use super::*;
+
+    #[test]
+    fn test_channel_gains() {
+        let (left, right) = channel_gains(1.0, 0.0, false);
+        assert!((left - 1.0).abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+
+        let (left, right) = channel_gains(0.5, -1.0, false);
+        assert!((left - 0.5).abs() < 1e-6 && right.abs() < 1e-6);
+
+        let (left, right) = channel_gains(1.0, 1.0, false);
+        assert!(left.abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+
+        let (left, right) = channel_gains(1.0, 0.5, false);
+        assert!(left > 0.0 && left < 1.0 && (right - 1.0).abs() < 1e-6);
+
+        let (left, right) = channel_gains(1.0, 0.0, true);
+        assert!((left - 1.0).abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+
+        let (left, right) = channel_gains(1.0, -1.0, true);
+        assert!((left - std::f32::consts::SQRT_2).abs() < 1e-6 && right.abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_gain_ramp_on_all_channels() {
+        let spec = AudioSpec::new(48000, 4).unwrap();
+        let source = BufferSource::new(spec, Arc::from([1.0; 16])).unwrap();
+        let mut voice = Voice::new(
+            SourceId::unique(),
+            Source::BufferSource(source),
+            PlayOpts::default().with_pan(-1.0),
+            spec.channels,
+            Quality::Medium,
+            NumFrames::new(0),
+        );
+        let mut scratch = VoiceScratch::new(spec, spec.channels, 4);
+        let mut buf = [0.0; 16];
+
+        voice.set_gain(0.0);
+        voice.mix_to_output_channels(&mut buf, &mut scratch);
+
+        // pan only affects front left and right
+        assert_eq!(
+            buf,
+            [
+                0.75, 0.0, 0.75, 0.75, 0.5, 0.0, 0.5, 0.5, 0.25, 0.0, 0.25, 0.25, 0.0, 0.0, 0.0,
+                0.0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fade() {
+        let mut fade = Fade::new(0.0, 1.0, 4);
+        let levels = (0..6).map(|_| fade.advance()).collect::<Vec<_>>();
+
+        assert_eq!(levels, vec![0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+        assert!(fade.is_complete());
+
+        let mut fade = Fade::new(1.0, 0.0, 2);
+        assert_eq!(fade.advance(), 0.5);
+        assert!(!fade.is_complete());
+        assert_eq!(fade.advance(), 0.0);
+        assert!(fade.is_complete());
+
+        assert!(Fade::new(1.0, 0.0, 0).is_complete());
+    }
+}
@@ -0,0 +1,80 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::time::{Duration, Instant};
+
+/// Setup for receiving periodic updates from the audio thread.
+///
+/// The latest value is published through a `single_value_channel` at most once every
+/// `interval`, so a slow client only ever sees the most recent update. Intervals shorter
+/// than the output buffer period are raised to it.
+#[derive(Debug)]
+pub struct Subscription<T> {
+    updater: single_value_channel::Updater<Option<T>>,
+    interval: Duration,
+}
+
+impl<T> Subscription<T> {
+    pub fn new(updater: single_value_channel::Updater<Option<T>>, interval: Duration) -> Self {
+        Self { updater, interval }
+    }
+}
+
+/// A subscription along with the time of its latest update.
+pub(crate) struct ActiveSubscription<T> {
+    subscription: Subscription<T>,
+    interval: Duration,
+    last_published: Instant,
+}
+
+impl<T> ActiveSubscription<T> {
+    /// Activate `subscription`, publishing at most once every `min_interval` whatever
+    /// its own interval, as updates any more often would keep the audio thread from
+    /// ever waiting.
+    pub fn new(subscription: Subscription<T>, min_interval: Duration) -> Self {
+        Self {
+            interval: subscription.interval.max(min_interval),
+            subscription,
+            last_published: Instant::now(),
+        }
+    }
+
+    /// When the next update is due.
+    pub fn next_due(&self) -> Instant {
+        self.last_published + self.interval
+    }
+
+    /// Publish the value produced by `make_value` if an update is due and a value is
+    /// available. Returns false once the receiving end has gone away.
+    pub fn publish_if_due(&mut self, make_value: impl FnOnce() -> Option<T>) -> bool {
+        if self.last_published.elapsed() < self.interval {
+            return true;
+        }
+
+        // also wait a full interval after finding no value, rather than asking again
+        // until there is one
+        self.last_published = Instant::now();
+
+        match make_value() {
+            Some(value) => self.subscription.updater.update(Some(value)).is_ok(),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_min_interval() {
+        let (_receiver, updater) = single_value_channel::channel::<u32>();
+        let subscription = ActiveSubscription::new(
+            Subscription::new(updater, Duration::ZERO),
+            Duration::from_millis(40),
+        );
+
+        assert!(subscription.next_due() >= Instant::now() + Duration::from_millis(30));
+    }
+}
@@ -0,0 +1,161 @@
+// MIT License
+//
+// Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, OnceLock,
+};
+
+use ringbuf::{
+    traits::{Observer, Producer, Split},
+    HeapCons, HeapProd, HeapRb,
+};
+
+use crate::types::AudioSpec;
+
+#[derive(Debug, Default)]
+struct TapShared {
+    spec: OnceLock<AudioSpec>,
+    overruns: AtomicU64,
+}
+
+/// Setup for receiving a copy of the final mix, see [`crate::Message::AddTap`].
+pub struct TapSetup {
+    buffer_tx: HeapProd<f32>,
+    shared: Arc<TapShared>,
+}
+
+impl std::fmt::Debug for TapSetup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "TapSetup(buffer capacity: {})",
+            self.buffer_tx.capacity()
+        ))
+    }
+}
+
+impl TapSetup {
+    /// Create a tap buffering up to `capacity` samples of interleaved output, along
+    /// with the receiving end.
+    pub fn new(capacity: usize) -> (Self, TapReceiver) {
+        let (buffer_tx, buffer_rx) = HeapRb::<f32>::new(capacity).split();
+        let shared = Arc::new(TapShared::default());
+
+        (
+            Self {
+                buffer_tx,
+                shared: Arc::clone(&shared),
+            },
+            TapReceiver { buffer_rx, shared },
+        )
+    }
+}
+
+/// Receiving end of a tap. The tap is removed from the audio thread once this is
+/// dropped.
+pub struct TapReceiver {
+    buffer_rx: HeapCons<f32>,
+    shared: Arc<TapShared>,
+}
+
+impl std::fmt::Debug for TapReceiver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "TapReceiver(spec: {:?}, buffer: {} of {}, overruns: {})",
+            self.spec(),
+            self.buffer_rx.occupied_len(),
+            self.buffer_rx.capacity(),
+            self.overruns(),
+        ))
+    }
+}
+
+impl TapReceiver {
+    /// Interleaved samples of the final mix, in the [`TapReceiver::spec`].
+    pub fn buffer_rx(&mut self) -> &mut HeapCons<f32> {
+        &mut self.buffer_rx
+    }
+
+    /// Spec of the tapped output, available once the tap has been added.
+    pub fn spec(&self) -> Option<AudioSpec> {
+        self.shared.spec.get().copied()
+    }
+
+    /// Number of output buffers that didn't fit in whole, with the frames that didn't
+    /// fit dropped.
+    pub fn overruns(&self) -> u64 {
+        self.shared.overruns.load(Ordering::Relaxed)
+    }
+}
+
+/// A tap added to the mixer.
+pub(crate) struct Tap {
+    spec: AudioSpec,
+    buffer_tx: HeapProd<f32>,
+    shared: Arc<TapShared>,
+}
+
+impl Tap {
+    pub fn new(setup: TapSetup, spec: AudioSpec) -> Self {
+        let _ = setup.shared.spec.set(spec);
+
+        Self {
+            spec,
+            buffer_tx: setup.buffer_tx,
+            shared: setup.shared,
+        }
+    }
+
+    /// Whether the receiving end is still around.
+    pub fn is_connected(&self) -> bool {
+        self.buffer_tx.read_is_held()
+    }
+
+    /// Copy as many whole frames of `buffer` as fit, without blocking.
+    pub fn write(&mut self, buffer: &[f32]) {
+        let chans = self.spec.channels.get() as usize;
+        let fitting_samples = (self.buffer_tx.vacant_len() / chans * chans).min(buffer.len());
+
+        self.buffer_tx.push_slice(&buffer[..fitting_samples]);
+
+        if fitting_samples < buffer.len() {
+            self.shared.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::traits::Consumer;
+
+    use super::*;
+
+    #[test]
+    fn test_tap() {
+        let spec = AudioSpec::new(48000, 2).unwrap();
+        let (setup, mut receiver) = TapSetup::new(7);
+
+        assert_eq!(receiver.spec(), None);
+
+        let mut tap = Tap::new(setup, spec);
+
+        assert_eq!(receiver.spec(), Some(spec));
+
+        tap.write(&[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(receiver.overruns(), 0);
+
+        // only whole frames are written
+        tap.write(&[5.0, 6.0, 7.0, 8.0]);
+        assert_eq!(receiver.overruns(), 1);
+
+        assert_eq!(
+            receiver.buffer_rx().pop_iter().collect::<Vec<_>>(),
+            [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]
+        );
+
+        assert!(tap.is_connected());
+        drop(receiver);
+        assert!(!tap.is_connected());
+    }
+}
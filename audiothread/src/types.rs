@@ -2,7 +2,11 @@
 //
 // Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
 
-use std::num::{NonZeroU32, NonZeroU8, NonZeroUsize};
+use std::{
+    num::{NonZeroU32, NonZeroU8, NonZeroUsize},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 use crate::error::ValueOutOfRangeError;
 
@@ -109,8 +113,102 @@ impl AudioSpec {
     }
 }
 
+/// Identifies a single playing source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceId(u64);
+
+impl SourceId {
+    /// Create an id that is unique within the current process.
+    pub fn unique() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        SourceId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Identifies a single inserted effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EffectId(u64);
+
+impl EffectId {
+    /// Create an id that is unique within the current process.
+    pub fn unique() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        EffectId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Playback rate of a source, changing both its speed and its pitch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaybackRate {
+    /// Speed relative to normal playback, e.g 2.0 for double speed (an octave up).
+    Ratio(f32),
+
+    /// Pitch relative to normal playback, e.g -12.0 for an octave down.
+    Semitones(f32),
+}
+
+impl PlaybackRate {
+    /// The rate as a ratio, limited to four octaves up or down.
+    pub(crate) fn ratio(&self) -> f64 {
+        let ratio = match self {
+            PlaybackRate::Ratio(ratio) => *ratio as f64,
+            PlaybackRate::Semitones(semitones) => 2f64.powf(*semitones as f64 / 12.0),
+        };
+
+        if ratio.is_finite() {
+            ratio.clamp(1.0 / 16.0, 16.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Which source makes way when a source is started with the voice limit reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoiceStealing {
+    /// Fade out the source that started first.
+    #[default]
+    Oldest,
+
+    /// Fade out the source that was quietest in the most recent output buffer. Sources
+    /// not heard yet, such as those scheduled to start later, are taken last.
+    Quietest,
+
+    /// Don't start the new source, reporting it with
+    /// [`crate::StatusMessage::SourceRejected`].
+    RejectNew,
+}
+
+/// Length of a fade-in or fade-out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FadeLength {
+    /// A number of frames in the spec of the source being faded.
+    Frames(NumFrames),
+
+    Duration(Duration),
+}
+
+impl FadeLength {
+    pub(crate) fn frames(&self, samplerate: Samplerate) -> usize {
+        match self {
+            FadeLength::Frames(frames) => frames.get(),
+            FadeLength::Duration(duration) => {
+                (duration.as_secs_f64() * samplerate.get() as f64).round() as usize
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum StreamState {
+pub enum StreamState {
     Streaming,
     Complete,
 }
@@ -2,12 +2,63 @@
 //
 // Copyright (c) 2024 Mikael Forsberg (github.com/mkforsb)
 
+use std::sync::mpsc::{channel, Sender};
+
 use audiothread::*;
 use ringbuf::{
-    traits::{Observer, Producer, Split},
+    traits::{Consumer, Observer, Producer, Split},
     HeapRb,
 };
 
+fn test_asset(name: &str) -> String {
+    format!(
+        "{}/test_assets/{name}",
+        std::env::var("CARGO_MANIFEST_DIR").unwrap()
+    )
+}
+
+fn frames(n: usize) -> NonZeroNumFrames {
+    NonZeroNumFrames::new(n).unwrap()
+}
+
+/// Wait for the audio thread to have processed all previously sent messages.
+fn sync(tx: &Sender<Message>) -> AudioSpec {
+    let (spec_tx, spec_rx) = channel::<AudioSpec>();
+    tx.send(Message::GetOutputSpec(spec_tx)).unwrap();
+    spec_rx.recv().unwrap()
+}
+
+/// Wait for the latest value published to `rx` to satisfy `f`, failing after a few
+/// seconds.
+fn wait_for<T: Clone>(
+    rx: &mut single_value_channel::Receiver<Option<T>>,
+    f: impl Fn(&T) -> bool,
+) -> T {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+
+    loop {
+        if let Some(value) = rx.latest().as_ref().filter(|value| f(value)) {
+            return value.clone();
+        }
+
+        assert!(
+            std::time::Instant::now() < deadline,
+            "timed out waiting for an update"
+        );
+
+        std::thread::yield_now();
+    }
+}
+
+/// Spawn an audio thread with `opts`, rendering on demand through the null backend.
+fn spawn_on_demand(opts: Opts) -> (Sender<Message>, NullBackendHandle, AudioThreadHandle) {
+    let (tx, rx) = channel::<Message>();
+    let (setup, output) = NullBackendSetup::on_demand();
+    let audiothread = spawn(rx, Some(opts.with_backend(BackendSetup::Null(setup)))).unwrap();
+
+    (tx, output, audiothread)
+}
+
 struct VibratoSaw {
     samplerate: f32,
     freq: f32,
@@ -34,7 +85,8 @@ fn test_pulled_source_saw_wave_playback() {
     let _audiothread = spawn(
         rx,
         Some(Opts::default().with_conversion_quality(Quality::Lowest)),
-    );
+    )
+    .unwrap();
 
     let saw_fn = |freq: f32| {
         let psbuf = HeapRb::<f32>::new(44100);
@@ -73,7 +125,11 @@ fn test_pulled_source_saw_wave_playback() {
             };
         });
 
-        let _ = tx.send(Message::CreatePulledSource(ps));
+        let _ = tx.send(Message::CreatePulledSource(
+            SourceId::unique(),
+            ps,
+            PlayOpts::default(),
+        ));
     };
 
     saw_fn(261.63);
@@ -82,3 +138,1261 @@ fn test_pulled_source_saw_wave_playback() {
 
     std::thread::sleep(std::time::Duration::from_secs(5));
 }
+
+#[test]
+fn test_null_backend_symphonia_source_playback() {
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default());
+
+    assert_eq!(sync(&tx), AudioSpec::new(48000, 2).unwrap());
+    assert!(output.render(frames(32)).unwrap().iter().all(|x| *x == 0.0));
+
+    tx.send(Message::PlaySymphoniaSource(
+        SourceId::unique(),
+        SymphoniaSource::from_file(&test_asset("square_1ch_48k_20smp.wav")).unwrap(),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    let buf = output.render(frames(32)).unwrap();
+
+    assert_eq!(buf.len(), 64);
+
+    for frame in buf[..40].chunks(2) {
+        assert_ne!(frame[0], 0.0);
+        assert_eq!(frame[0], frame[1]);
+    }
+
+    assert!(buf[40..].iter().all(|x| *x == 0.0));
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_null_backend_pulled_source_playback() {
+    let spec = AudioSpec::new(44100, 2).unwrap();
+
+    let (tx, output, audiothread) =
+        spawn_on_demand(Opts::default().with_spec(spec).with_buffer_size(frames(16)));
+
+    let (mut buffer_tx, buffer_rx) = HeapRb::<f32>::new(256).split();
+    let (pull_tx, _pull_rx) = channel::<PulledSourcePullRequest>();
+    let samples = (0..200).map(|x| x as f32 / 200.0).collect::<Vec<_>>();
+
+    buffer_tx.push_slice(&samples);
+
+    tx.send(Message::CreatePulledSource(
+        SourceId::unique(),
+        PulledSourceSetup::new("Ramp", spec, buffer_rx, pull_tx),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    let buf = output.render(frames(128)).unwrap();
+
+    assert_eq!(&buf[..200], samples.as_slice());
+    assert!(buf[200..].iter().all(|x| *x == 0.0));
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_pulled_source_pull_requests() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let (mut buffer_tx, buffer_rx) = HeapRb::<f32>::new(100).split();
+    let (pull_tx, pull_rx) = channel::<PulledSourcePullRequest>();
+    let timeout = std::time::Duration::from_secs(5);
+
+    tx.send(Message::CreatePulledSource(
+        SourceId::unique(),
+        PulledSourceSetup::new("Pulled", spec, buffer_rx, pull_tx).with_low_water_mark(0.25),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    let request = pull_rx.recv_timeout(timeout).unwrap();
+
+    assert_eq!(request.frames, NumFrames::new(100));
+    assert_eq!(request.stream_time, NumFrames::new(0));
+    assert_eq!(request.underruns, 0);
+
+    // playing before the producer has provided anything is not an underrun
+    assert_eq!(output.render(frames(4)).unwrap(), [0.0; 4]);
+
+    buffer_tx.push_slice(&[1.0; 80]);
+
+    request
+        .response_tx
+        .send(PulledSourcePullReply::FramesProvided(NumFrames::new(80)))
+        .unwrap();
+
+    sync(&tx);
+    assert!(pull_rx.try_recv().is_err());
+
+    assert_eq!(output.render(frames(60)).unwrap(), [1.0; 60]);
+
+    let request = pull_rx.recv_timeout(timeout).unwrap();
+
+    assert_eq!(request.frames, NumFrames::new(80));
+    assert_eq!(request.stream_time, NumFrames::new(84));
+    assert_eq!(request.underruns, 0);
+
+    // run dry by replying without providing anything
+    request
+        .response_tx
+        .send(PulledSourcePullReply::FramesProvided(NumFrames::new(0)))
+        .unwrap();
+
+    sync(&tx);
+
+    let buf = output.render(frames(40)).unwrap();
+
+    assert_eq!(&buf[..20], [1.0; 20]);
+    assert!(buf[20..].iter().all(|x| *x == 0.0));
+
+    let request = pull_rx.recv_timeout(timeout).unwrap();
+
+    assert_eq!(request.frames, NumFrames::new(100));
+    assert_eq!(request.stream_time, NumFrames::new(104));
+    assert_eq!(request.underruns, 1);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_spawn_reports_backend_startup_failure() {
+    let (_tx, rx) = channel::<Message>();
+
+    let result = spawn(
+        rx,
+        Some(
+            Opts::default().with_backend(BackendSetup::Custom(Box::new(|_opts| {
+                Err(BackendError("No sound for you".to_string()))
+            }))),
+        ),
+    );
+
+    match result {
+        Err(StartupError::BackendError(BackendError(msg))) => assert_eq!(msg, "No sound for you"),
+        _ => panic!("Expected a startup error"),
+    }
+}
+
+#[test]
+fn test_backend_failure_status_message() {
+    let (_tx, output, audiothread) = spawn_on_demand(Opts::default());
+
+    drop(output);
+
+    let status = audiothread
+        .status_rx()
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .unwrap();
+
+    assert!(matches!(status, StatusMessage::BackendFailed(_)));
+    assert!(matches!(
+        audiothread.status_rx().recv().unwrap(),
+        StatusMessage::ShutDown
+    ));
+
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_source_stop_pause_resume() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let info = |id: SourceId| {
+        let (info_tx, info_rx) = channel::<Option<SourceInfo>>();
+        tx.send(Message::GetSourceInfo(id, info_tx)).unwrap();
+        info_rx.recv().unwrap()
+    };
+
+    let (mut buffer_tx, buffer_rx) = HeapRb::<f32>::new(64).split();
+    let (pull_tx, _pull_rx) = channel::<PulledSourcePullRequest>();
+    let pulled_id = SourceId::unique();
+
+    buffer_tx.push_slice(&[0.5; 64]);
+
+    tx.send(Message::CreatePulledSource(
+        pulled_id,
+        PulledSourceSetup::new("Constant", spec, buffer_rx, pull_tx),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    let symphonia_id = SourceId::unique();
+
+    tx.send(Message::PlaySymphoniaSource(
+        symphonia_id,
+        SymphoniaSource::from_file(&test_asset("square_1ch_48k_20smp.wav")).unwrap(),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    tx.send(Message::PauseSource(symphonia_id)).unwrap();
+
+    let pulled_info = info(pulled_id).unwrap();
+
+    assert_eq!(pulled_info.spec, spec);
+    assert_eq!(pulled_info.stream_state, StreamState::Streaming);
+    assert!(!pulled_info.paused);
+    assert_eq!(pulled_info.position, NumFrames::new(0));
+    assert!(info(symphonia_id).unwrap().paused);
+
+    assert_eq!(output.render(frames(16)).unwrap(), vec![0.5; 16]);
+    assert_eq!(info(pulled_id).unwrap().position, NumFrames::new(16));
+    assert_eq!(info(symphonia_id).unwrap().position, NumFrames::new(0));
+
+    tx.send(Message::StopSource(pulled_id, None)).unwrap();
+    tx.send(Message::ResumeSource(symphonia_id)).unwrap();
+
+    assert!(info(pulled_id).is_none());
+    let buf = output.render(frames(16)).unwrap();
+
+    assert!(buf[..10].iter().all(|x| *x > 0.99));
+    assert!(buf[10..].iter().all(|x| *x < -0.99));
+    assert_eq!(info(symphonia_id).unwrap().position, NumFrames::new(16));
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_source_gain_pan_and_master_volume() {
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_master_volume(0.5));
+
+    let (mut buffer_tx, buffer_rx) = HeapRb::<f32>::new(256).split();
+    let (pull_tx, _pull_rx) = channel::<PulledSourcePullRequest>();
+    let id = SourceId::unique();
+
+    buffer_tx.push_slice(&[1.0; 256]);
+
+    tx.send(Message::CreatePulledSource(
+        id,
+        PulledSourceSetup::new(
+            "Mono",
+            AudioSpec::new(48000, 1).unwrap(),
+            buffer_rx,
+            pull_tx,
+        ),
+        PlayOpts::default().with_gain(0.5).with_pan(-1.0),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    let buf = output.render(frames(16)).unwrap();
+
+    for frame in buf.chunks(2) {
+        assert!((frame[0] - 0.25).abs() < 1e-6);
+        assert!(frame[1].abs() < 1e-6);
+    }
+
+    tx.send(Message::SetSourcePan(id, 1.0)).unwrap();
+    tx.send(Message::SetMasterVolume(1.0)).unwrap();
+    sync(&tx);
+
+    // changes are ramped in over one buffer
+    let buf = output.render(frames(16)).unwrap();
+
+    assert!(buf[0] > 0.0 && buf[0] < 0.25);
+    assert!((buf[31] - 0.5).abs() < 1e-6);
+
+    let buf = output.render(frames(16)).unwrap();
+
+    for frame in buf.chunks(2) {
+        assert!(frame[0].abs() < 1e-6);
+        assert!((frame[1] - 0.5).abs() < 1e-6);
+    }
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_source_fade_in_and_fade_out() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let (mut buffer_tx, buffer_rx) = HeapRb::<f32>::new(64).split();
+    let (pull_tx, _pull_rx) = channel::<PulledSourcePullRequest>();
+    let id = SourceId::unique();
+
+    buffer_tx.push_slice(&[0.5; 64]);
+
+    tx.send(Message::CreatePulledSource(
+        id,
+        PulledSourceSetup::new("Constant", spec, buffer_rx, pull_tx),
+        PlayOpts::default().with_fade_in(FadeLength::Frames(NumFrames::new(4))),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    assert_eq!(
+        output.render(frames(8)).unwrap(),
+        vec![0.125, 0.25, 0.375, 0.5, 0.5, 0.5, 0.5, 0.5]
+    );
+
+    tx.send(Message::StopSource(
+        id,
+        Some(FadeLength::Frames(NumFrames::new(4))),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    assert_eq!(
+        output.render(frames(8)).unwrap(),
+        vec![0.375, 0.25, 0.125, 0.0, 0.0, 0.0, 0.0, 0.0]
+    );
+
+    assert_eq!(output.render(frames(8)).unwrap(), vec![0.0; 8]);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_master_limiter() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) =
+        spawn_on_demand(Opts::default().with_spec(spec).with_limiter(Limiter::Peak {
+            ceiling: 1.0,
+            release: std::time::Duration::from_millis(50),
+        }));
+
+    let gain_reduction = || {
+        let (gr_tx, gr_rx) = channel::<f32>();
+        tx.send(Message::GetGainReduction(gr_tx)).unwrap();
+        gr_rx.recv().unwrap()
+    };
+
+    for _ in 0..4 {
+        let (mut buffer_tx, buffer_rx) = HeapRb::<f32>::new(64).split();
+        let (pull_tx, _pull_rx) = channel::<PulledSourcePullRequest>();
+
+        buffer_tx.push_slice(&[0.5; 64]);
+
+        tx.send(Message::CreatePulledSource(
+            SourceId::unique(),
+            PulledSourceSetup::new("Constant", spec, buffer_rx, pull_tx),
+            PlayOpts::default(),
+        ))
+        .unwrap();
+    }
+
+    sync(&tx);
+
+    assert_eq!(gain_reduction(), 0.0);
+    assert!(output
+        .render(frames(16))
+        .unwrap()
+        .iter()
+        .all(|x| (*x - 1.0).abs() < 1e-6));
+    assert!((gain_reduction() - 6.0206).abs() < 1e-3);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_metering() {
+    // updates are published at most once per buffer period
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_buffer_size(frames(48)));
+
+    let (mut buffer_tx, buffer_rx) = HeapRb::<f32>::new(64).split();
+    let (pull_tx, _pull_rx) = channel::<PulledSourcePullRequest>();
+    let id = SourceId::unique();
+
+    buffer_tx.push_slice(&[0.5, -0.5].repeat(32));
+
+    tx.send(Message::CreatePulledSource(
+        id,
+        PulledSourceSetup::new(
+            "Square",
+            AudioSpec::new(48000, 1).unwrap(),
+            buffer_rx,
+            pull_tx,
+        ),
+        PlayOpts::default().with_pan(-1.0),
+    ))
+    .unwrap();
+
+    let (mut meter_rx, meter_updater) = single_value_channel::channel::<MeterReading>();
+
+    tx.send(Message::SetMetering(Some(MeteringSetup::new(
+        meter_updater,
+        std::time::Duration::ZERO,
+    ))))
+    .unwrap();
+
+    sync(&tx);
+    output.render(frames(16)).unwrap();
+
+    let reading = wait_for(&mut meter_rx, |reading| reading.sources.contains_key(&id));
+
+    assert_eq!(reading.master.len(), 2);
+    assert!((reading.master[0].peak - 0.5).abs() < 1e-6);
+    assert!((reading.master[0].rms - 0.5).abs() < 1e-6);
+    assert_eq!(reading.master[1], Levels::default());
+
+    let levels = reading.sources[&id];
+
+    assert!((levels.peak - 0.5).abs() < 1e-6);
+    assert!((levels.rms - 0.5f32 / 2.0f32.sqrt()).abs() < 1e-6);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_buffer_source_concurrent_playback() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let source = BufferSource::decode(
+        SymphoniaSource::from_file(&test_asset("square_1ch_48k_20smp.wav")).unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(source.data().len(), 20);
+
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        source.clone(),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        source.clone(),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    let buf = output.render(frames(24)).unwrap();
+
+    assert!(buf[..10].iter().all(|x| *x > 1.99));
+    assert!(buf[10..20].iter().all(|x| *x < -1.99));
+    assert_eq!(buf[20..], [0.0; 4]);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_render_large_buffer() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    // two seconds mixed in one go
+    let (tx, output, audiothread) = spawn_on_demand(
+        Opts::default()
+            .with_spec(spec)
+            .with_buffer_size(frames(96000)),
+    );
+
+    // one source converted from another sample rate, one not
+    for source_spec in [AudioSpec::new(44100, 1).unwrap(), spec] {
+        let samplerate = source_spec.samplerate.get() as usize;
+
+        tx.send(Message::PlayBufferSource(
+            SourceId::unique(),
+            BufferSource::new(source_spec, vec![0.25; 3 * samplerate].into()).unwrap(),
+            PlayOpts::default(),
+        ))
+        .unwrap();
+    }
+
+    sync(&tx);
+
+    let buf = output.render(frames(96000)).unwrap();
+
+    assert_eq!(buf.len(), 96000);
+    assert!(buf[48000..].iter().all(|x| (x - 0.5).abs() < 1e-3));
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_source_position_reporting() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    // updates are published at most once per buffer period
+    let (tx, output, audiothread) =
+        spawn_on_demand(Opts::default().with_spec(spec).with_buffer_size(frames(48)));
+
+    let (mut info_rx, info_updater) = single_value_channel::channel();
+
+    tx.send(Message::SetSourceInfoSubscription(Some(Subscription::new(
+        info_updater,
+        std::time::Duration::ZERO,
+    ))))
+    .unwrap();
+
+    let id = SourceId::unique();
+
+    tx.send(Message::PlaySymphoniaSource(
+        id,
+        SymphoniaSource::from_file(&test_asset("square_1ch_48k_20smp.wav"))
+            .unwrap()
+            .with_start_offset(NumFrames::new(4)),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    sync(&tx);
+    output.render(frames(8)).unwrap();
+
+    let info = wait_for(&mut info_rx, |infos| {
+        infos.get(&id).is_some_and(|info| info.position.get() > 0)
+    })[&id]
+        .clone();
+
+    assert_eq!(info.position, NumFrames::new(8));
+    assert_eq!(info.stream_position, Some(NumFrames::new(12)));
+    assert_eq!(info.audible_position, Some(NumFrames::new(12)));
+    assert_eq!(info.stream_state, StreamState::Streaming);
+
+    output.render(frames(16)).unwrap();
+
+    let info = wait_for(&mut info_rx, |infos| {
+        infos.get(&id).is_some_and(|info| info.position.get() > 8)
+    })[&id]
+        .clone();
+
+    assert_eq!(info.stream_position, Some(NumFrames::new(20)));
+    assert_eq!(info.stream_state, StreamState::Complete);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_realtime_null_backend_underruns() {
+    let (tx, rx) = channel::<Message>();
+    let (output_tx, output_rx) = channel::<Vec<f32>>();
+
+    let audiothread = spawn(
+        rx,
+        Some(
+            Opts::default()
+                .with_buffer_size(frames(4800))
+                .with_backend(BackendSetup::Null(NullBackendSetup::realtime_with_output(
+                    output_tx,
+                ))),
+        ),
+    )
+    .unwrap();
+
+    let started = std::time::Instant::now();
+
+    for _ in 0..3 {
+        let buf = output_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+
+        assert_eq!(buf.len(), 9600);
+    }
+
+    // buffers are rendered as they become due, not ahead of time
+    assert!(started.elapsed() >= std::time::Duration::from_millis(250));
+
+    // whether this machine kept up is not for the test to say, only that it's reported
+    let (underruns_tx, underruns_rx) = channel::<u64>();
+    tx.send(Message::GetUnderruns(underruns_tx)).unwrap();
+
+    assert!(underruns_rx
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .is_ok());
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_global_pause_resume() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let ramp = BufferSource::new(spec, (1..=16).map(|x| x as f32).collect()).unwrap();
+    let ones = BufferSource::new(spec, vec![1000.0; 16].into()).unwrap();
+
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        ramp,
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    tx.send(Message::Pause(None)).unwrap();
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [0.0; 4]);
+
+    // sources started while paused wait for the output to be resumed
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        ones,
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [0.0; 4]);
+
+    tx.send(Message::Resume(None)).unwrap();
+    sync(&tx);
+
+    assert_eq!(
+        output.render(frames(4)).unwrap(),
+        [1001.0, 1002.0, 1003.0, 1004.0]
+    );
+
+    tx.send(Message::Pause(Some(
+        SourceMatcher::new().match_type(SourceType::BufferSource),
+    )))
+    .unwrap();
+
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [0.0; 4]);
+
+    tx.send(Message::Resume(Some(SourceMatcher::new())))
+        .unwrap();
+    sync(&tx);
+
+    assert_eq!(
+        output.render(frames(4)).unwrap(),
+        [1005.0, 1006.0, 1007.0, 1008.0]
+    );
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_scheduled_playback() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let stream_time = || {
+        let (time_tx, time_rx) = channel::<NumFrames>();
+        tx.send(Message::GetStreamTime(time_tx)).unwrap();
+        time_rx.recv().unwrap()
+    };
+
+    assert_eq!(stream_time(), NumFrames::new(0));
+
+    output.render(frames(10)).unwrap();
+
+    assert_eq!(stream_time(), NumFrames::new(10));
+
+    let ramp = BufferSource::new(spec, (1..=4).map(|x| x as f32).collect()).unwrap();
+
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        ramp.clone(),
+        PlayOpts::default().with_start_time(NumFrames::new(13)),
+    ))
+    .unwrap();
+
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        ramp.clone(),
+        PlayOpts::default().with_start_time(NumFrames::new(20)),
+    ))
+    .unwrap();
+
+    // already passed, so started right away
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        ramp,
+        PlayOpts::default()
+            .with_gain(100.0)
+            .with_start_time(NumFrames::new(5)),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    assert_eq!(
+        output.render(frames(8)).unwrap(),
+        [100.0, 200.0, 300.0, 401.0, 2.0, 3.0, 4.0, 0.0]
+    );
+
+    assert_eq!(
+        output.render(frames(8)).unwrap(),
+        [0.0, 0.0, 1.0, 2.0, 3.0, 4.0, 0.0, 0.0]
+    );
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_output_tap() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let (tap_setup, mut tap) = TapSetup::new(12);
+
+    tx.send(Message::AddTap(tap_setup)).unwrap();
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        BufferSource::new(spec, (1..=16).map(|x| x as f32).collect()).unwrap(),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    assert_eq!(tap.spec(), Some(spec));
+
+    let first = output.render(frames(8)).unwrap();
+    let second = output.render(frames(8)).unwrap();
+
+    assert_eq!(tap.overruns(), 1);
+    assert_eq!(tap.buffer_rx().occupied_len(), 12);
+
+    let tapped = tap.buffer_rx().pop_iter().collect::<Vec<_>>();
+
+    assert_eq!(tapped[..8], first);
+    assert_eq!(tapped[8..], second[..4]);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_recording() {
+    let spec = AudioSpec::new(48000, 2).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let path = std::env::temp_dir().join(format!("audiothread-test-{}.wav", std::process::id()));
+
+    tx.send(Message::StartRecording {
+        path: path.clone(),
+        format: RecordingFormat::Int16,
+    })
+    .unwrap();
+
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        BufferSource::new(spec, vec![0.5, -0.25, 1.5, -1.5].into()).unwrap(),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    sync(&tx);
+    output.render(frames(4)).unwrap();
+
+    tx.send(Message::StopRecording).unwrap();
+
+    match audiothread
+        .status_rx()
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .unwrap()
+    {
+        StatusMessage::RecordingFinished(finished) => assert_eq!(finished, path),
+        status => panic!("Unexpected status {status:?}"),
+    }
+
+    let mut reader = hound::WavReader::open(&path).unwrap();
+
+    assert_eq!(reader.spec().channels, 2);
+    assert_eq!(reader.spec().sample_rate, 48000);
+    assert_eq!(reader.spec().bits_per_sample, 16);
+    assert_eq!(
+        reader
+            .samples::<i16>()
+            .map(|s| s.unwrap())
+            .collect::<Vec<_>>(),
+        [16383, -8191, 32767, -32767, 0, 0, 0, 0]
+    );
+
+    std::fs::remove_file(&path).unwrap();
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_buses() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    tx.send(Message::SetBusGain(String::from("fx"), 0.5))
+        .unwrap();
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [0.0; 4]);
+
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        BufferSource::new(spec, (1..=32).map(|x| x as f32).collect()).unwrap(),
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        BufferSource::new(spec, vec![10.0; 32].into()).unwrap(),
+        PlayOpts::default().with_bus("fx"),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [6.0, 7.0, 8.0, 9.0]);
+
+    // muting fades the bus out over one buffer
+    tx.send(Message::SetBusMute(String::from("fx"), true))
+        .unwrap();
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [8.75, 8.5, 8.25, 8.0]);
+    assert_eq!(output.render(frames(4)).unwrap(), [9.0, 10.0, 11.0, 12.0]);
+
+    tx.send(Message::SetBusMute(String::from("fx"), false))
+        .unwrap();
+    tx.send(Message::SetBusSolo(String::from("fx"), true))
+        .unwrap();
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [11.0, 9.5, 7.5, 5.0]);
+    assert_eq!(output.render(frames(4)).unwrap(), [5.0; 4]);
+
+    tx.send(Message::DropAllMatching(
+        SourceMatcher::new().match_bus("fx"),
+        None,
+    ))
+    .unwrap();
+    tx.send(Message::SetBusSolo(String::from("fx"), false))
+        .unwrap();
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [5.25, 11.0, 17.25, 24.0]);
+    assert_eq!(output.render(frames(4)).unwrap(), [25.0, 26.0, 27.0, 28.0]);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_effects() {
+    struct Gain(f32);
+
+    impl Effect for Gain {
+        fn process(&mut self, _spec: AudioSpec, buffer: &mut [f32]) {
+            buffer.iter_mut().for_each(|sample| *sample *= self.0);
+        }
+    }
+
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let (delay_id, gain_id) = (EffectId::unique(), EffectId::unique());
+
+    tx.send(Message::InsertEffect(
+        Some(String::from("fx")),
+        delay_id,
+        Box::new(FeedbackDelay::new(
+            std::time::Duration::from_secs_f64(2.0 / 48000.0),
+            0.5,
+            1.0,
+        )),
+    ))
+    .unwrap();
+
+    tx.send(Message::InsertEffect(None, gain_id, Box::new(Gain(2.0))))
+        .unwrap();
+
+    tx.send(Message::PlayBufferSource(
+        SourceId::unique(),
+        BufferSource::new(spec, vec![1.0].into()).unwrap(),
+        PlayOpts::default().with_bus("fx"),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    assert_eq!(
+        output.render(frames(8)).unwrap(),
+        [0.0, 0.0, 2.0, 0.0, 1.0, 0.0, 0.5, 0.0]
+    );
+
+    // echoes keep going after the source has completed
+    assert_eq!(output.render(frames(4)).unwrap(), [0.25, 0.0, 0.125, 0.0]);
+
+    tx.send(Message::SetEffectBypass(gain_id, true)).unwrap();
+    sync(&tx);
+
+    assert_eq!(
+        output.render(frames(4)).unwrap(),
+        [0.03125, 0.0, 0.015625, 0.0]
+    );
+
+    tx.send(Message::RemoveEffect(delay_id)).unwrap();
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [0.0; 4]);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_playback_rate() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(
+        Opts::default()
+            .with_spec(spec)
+            .with_conversion_quality(Quality::Lowest),
+    );
+
+    let position = |id: SourceId| {
+        let (info_tx, info_rx) = channel::<Option<SourceInfo>>();
+        tx.send(Message::GetSourceInfo(id, info_tx)).unwrap();
+        info_rx.recv().unwrap().unwrap().position.get()
+    };
+
+    let id = SourceId::unique();
+
+    tx.send(Message::PlayBufferSource(
+        id,
+        BufferSource::new(spec, (0..48000).map(|x| x as f32 / 48000.0).collect()).unwrap(),
+        PlayOpts::default().with_rate(PlaybackRate::Ratio(2.0)),
+    ))
+    .unwrap();
+
+    sync(&tx);
+
+    let rendered = output.render(frames(1000)).unwrap();
+
+    assert!(position(id).abs_diff(2000) <= 8);
+
+    // the ramp rises twice as fast
+    let slope = (rendered[900] - rendered[500]) / 400.0;
+    assert!((slope - 2.0 / 48000.0).abs() < 1e-6);
+
+    tx.send(Message::SetSourceRate(id, PlaybackRate::Semitones(-12.0)))
+        .unwrap();
+    sync(&tx);
+
+    // the rate changes smoothly over the first buffer
+    output.render(frames(1000)).unwrap();
+
+    let before = position(id);
+    let rendered = output.render(frames(1000)).unwrap();
+
+    assert!((position(id) - before).abs_diff(500) <= 8);
+
+    let slope = (rendered[900] - rendered[500]) / 400.0;
+    assert!((slope - 0.5 / 48000.0).abs() < 1e-6);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_queue_source() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let item =
+        |data: &[f32]| QueueItem::BufferSource(BufferSource::new(spec, data.into()).unwrap());
+    let (queue_id, first, second) = (SourceId::unique(), SourceId::unique(), SourceId::unique());
+
+    let mut queue = QueueSource::new(spec);
+    queue.append(first, item(&[1.0, 2.0, 3.0])).unwrap();
+
+    tx.send(Message::PlayQueueSource(
+        queue_id,
+        queue,
+        PlayOpts::default(),
+    ))
+    .unwrap();
+
+    tx.send(Message::AppendToQueue {
+        queue: queue_id,
+        item: second,
+        source: item(&[4.0, 5.0, 6.0]),
+    })
+    .unwrap();
+
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [1.0, 2.0, 3.0, 4.0]);
+    sync(&tx);
+
+    match audiothread
+        .status_rx()
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .unwrap()
+    {
+        StatusMessage::QueueItemFinished { queue, item } => {
+            assert_eq!((queue, item), (queue_id, first))
+        }
+        status => panic!("Unexpected status {status:?}"),
+    }
+
+    tx.send(Message::ClearQueue(queue_id)).unwrap();
+    tx.send(Message::AppendToQueue {
+        queue: queue_id,
+        item: SourceId::unique(),
+        source: item(&[7.0]),
+    })
+    .unwrap();
+    tx.send(Message::ClearQueue(queue_id)).unwrap();
+    sync(&tx);
+
+    // the item playing is not cleared
+    assert_eq!(output.render(frames(4)).unwrap(), [5.0, 6.0, 0.0, 0.0]);
+    sync(&tx);
+
+    match audiothread
+        .status_rx()
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .unwrap()
+    {
+        StatusMessage::QueueItemFinished { queue, item } => {
+            assert_eq!((queue, item), (queue_id, second))
+        }
+        status => panic!("Unexpected status {status:?}"),
+    }
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_source_matchers() {
+    let spec = AudioSpec::new(48000, 1).unwrap();
+    let stereo = AudioSpec::new(48000, 2).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(Opts::default().with_spec(spec));
+
+    let is_playing = |id: SourceId| {
+        let (info_tx, info_rx) = channel::<Option<SourceInfo>>();
+        tx.send(Message::GetSourceInfo(id, info_tx)).unwrap();
+        info_rx.recv().unwrap().is_some()
+    };
+
+    let play_buffer = |spec: AudioSpec, tag: &str| {
+        let id = SourceId::unique();
+
+        tx.send(Message::PlayBufferSource(
+            id,
+            BufferSource::new(spec, vec![0.0; 4800 * spec.channels.get() as usize].into()).unwrap(),
+            PlayOpts::default().with_tag(tag),
+        ))
+        .unwrap();
+
+        id
+    };
+
+    let (pull_tx, _pull_rx) = channel::<PulledSourcePullRequest>();
+    let mut buffer_txs = Vec::new();
+
+    let mut create_pulled = |name: &str| {
+        let id = SourceId::unique();
+        let (buffer_tx, buffer_rx) = HeapRb::<f32>::new(16).split();
+        buffer_txs.push(buffer_tx);
+
+        tx.send(Message::CreatePulledSource(
+            id,
+            PulledSourceSetup::new(name, spec, buffer_rx, pull_tx.clone()),
+            PlayOpts::default(),
+        ))
+        .unwrap();
+
+        id
+    };
+
+    let first = play_buffer(spec, "browser");
+
+    sync(&tx);
+    output.render(frames(4)).unwrap();
+
+    let second = play_buffer(spec, "browser");
+    let stereo_id = play_buffer(stereo, "browser");
+    let preview_1 = create_pulled("preview-1");
+    let preview_2 = create_pulled("preview-2");
+    let metronome = create_pulled("metronome");
+
+    tx.send(Message::DropAllMatching(
+        SourceMatcher::new()
+            .match_tag("browser")
+            .match_started_before(NumFrames::new(4)),
+        None,
+    ))
+    .unwrap();
+
+    assert!(!is_playing(first));
+    assert!(is_playing(second));
+
+    tx.send(Message::DropAllMatching(
+        SourceMatcher::new().match_spec(stereo),
+        None,
+    ))
+    .unwrap();
+
+    assert!(!is_playing(stereo_id));
+    assert!(is_playing(second));
+
+    tx.send(Message::DropAllMatching(
+        SourceMatcher::new().match_name_prefix("preview-"),
+        None,
+    ))
+    .unwrap();
+
+    assert!(!is_playing(preview_1));
+    assert!(!is_playing(preview_2));
+    assert!(is_playing(metronome));
+
+    tx.send(Message::DropAllMatching(
+        SourceMatcher::new()
+            .match_name("metronome")
+            .match_id(second),
+        None,
+    ))
+    .unwrap();
+
+    assert!(is_playing(metronome));
+    assert!(is_playing(second));
+
+    tx.send(Message::DropAllMatching(
+        SourceMatcher::new().match_name("metronome"),
+        None,
+    ))
+    .unwrap();
+
+    assert!(!is_playing(metronome));
+    assert!(is_playing(second));
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
+
+#[test]
+fn test_voice_limit() {
+    for (stealing, expected) in [
+        (VoiceStealing::Oldest, 11.0),
+        (VoiceStealing::Quietest, 110.0),
+        (VoiceStealing::RejectNew, 101.0),
+    ] {
+        let spec = AudioSpec::new(48000, 1).unwrap();
+
+        let (tx, output, audiothread) = spawn_on_demand(
+            Opts::default()
+                .with_spec(spec)
+                .with_voice_limit(2, stealing),
+        );
+
+        let play = |value: f32| {
+            let id = SourceId::unique();
+
+            tx.send(Message::PlayBufferSource(
+                id,
+                BufferSource::new(spec, vec![value; 48000].into()).unwrap(),
+                PlayOpts::default(),
+            ))
+            .unwrap();
+
+            id
+        };
+
+        play(100.0);
+        play(1.0);
+        sync(&tx);
+
+        assert_eq!(output.render(frames(4)).unwrap(), [101.0; 4]);
+
+        let third = play(10.0);
+        sync(&tx);
+
+        // the stolen source fades out over 5ms
+        output.render(frames(480)).unwrap();
+
+        assert_eq!(output.render(frames(4)).unwrap(), [expected; 4]);
+
+        if stealing == VoiceStealing::RejectNew {
+            match audiothread
+                .status_rx()
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .unwrap()
+            {
+                StatusMessage::SourceRejected(id) => assert_eq!(id, third),
+                status => panic!("Unexpected status {status:?}"),
+            }
+        }
+
+        tx.send(Message::Shutdown).unwrap();
+        audiothread.join().unwrap();
+    }
+
+    // sources scheduled for later are not taken for the quietest
+    let spec = AudioSpec::new(48000, 1).unwrap();
+
+    let (tx, output, audiothread) = spawn_on_demand(
+        Opts::default()
+            .with_spec(spec)
+            .with_voice_limit(2, VoiceStealing::Quietest),
+    );
+
+    let play = |value: f32, opts: PlayOpts| {
+        tx.send(Message::PlayBufferSource(
+            SourceId::unique(),
+            BufferSource::new(spec, vec![value; 48000].into()).unwrap(),
+            opts,
+        ))
+        .unwrap();
+    };
+
+    play(100.0, PlayOpts::default());
+    play(
+        1.0,
+        PlayOpts::default().with_start_time(NumFrames::new(48000)),
+    );
+    sync(&tx);
+
+    assert_eq!(output.render(frames(4)).unwrap(), [100.0; 4]);
+
+    play(10.0, PlayOpts::default());
+    sync(&tx);
+    output.render(frames(480)).unwrap();
+
+    assert_eq!(output.render(frames(4)).unwrap(), [10.0; 4]);
+
+    tx.send(Message::Shutdown).unwrap();
+    audiothread.join().unwrap();
+}
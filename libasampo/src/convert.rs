@@ -142,7 +142,7 @@ pub fn convert<T>(
 where
     T: SampleValueConvert + Copy,
 {
-    if samples.len() % input_channels as usize != 0 {
+    if !samples.len().is_multiple_of(input_channels as usize) {
         return Err(Error::SampleConversionError(format!(
             "Buffer length ({}) - channel count ({}) mismatch",
             samples.len(),
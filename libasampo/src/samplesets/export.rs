@@ -381,13 +381,10 @@ mod tests {
             self.0
                 .lock()
                 .map_err(|e| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        Error::IoError {
-                            uri: "???".to_string(),
-                            details: e.to_string(),
-                        },
-                    )
+                    std::io::Error::other(Error::IoError {
+                        uri: "???".to_string(),
+                        details: e.to_string(),
+                    })
                 })?
                 .extend_from_slice(buf);
             Ok(buf.len())
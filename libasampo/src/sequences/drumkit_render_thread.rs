@@ -50,6 +50,11 @@ pub enum Message {
 struct State {
     renderer: DrumkitSequenceRenderer,
     paused: bool,
+    source_paused: bool,
+    source_id: audiothread::SourceId,
+    audiothread_tx: Sender<audiothread::Message>,
+    channels: usize,
+    underruns: u64,
     buffer: Vec<f32>,
     buffer_tx: HeapProd<f32>,
     pull_request_rx: Receiver<audiothread::PulledSourcePullRequest>,
@@ -90,21 +95,29 @@ impl State {
         );
 
         let (buffer_tx, buffer_rx) = HeapRb::<f32>::new(bufsize).split();
+        let source_id = audiothread::SourceId::unique();
 
         audiothread_tx
             .send(audiothread::Message::CreatePulledSource(
+                source_id,
                 audiothread::PulledSourceSetup::new(
                     "DrumkitSequence",
                     output_spec,
                     buffer_rx,
                     pull_request_tx,
                 ),
+                audiothread::PlayOpts::default(),
             ))
             .map_err(|e| Error::ChannelError(e.to_string()))?;
 
         Ok(Self {
             renderer,
             paused: true,
+            source_paused: false,
+            source_id,
+            audiothread_tx,
+            channels: output_spec.channels.get() as usize,
+            underruns: 0,
             buffer,
             buffer_tx,
             pull_request_rx,
@@ -113,6 +126,25 @@ impl State {
             event_tx,
         })
     }
+
+    /// Pause or resume the pulled source on the audiothread side. While paused the
+    /// source stops pulling, and already rendered audio is kept for when it resumes.
+    fn set_source_paused(&mut self, paused: bool) {
+        if paused == self.source_paused {
+            return;
+        }
+
+        let message = if paused {
+            audiothread::Message::PauseSource(self.source_id)
+        } else {
+            audiothread::Message::ResumeSource(self.source_id)
+        };
+
+        match self.audiothread_tx.send(message) {
+            Ok(_) => self.source_paused = paused,
+            Err(e) => log::log!(log::Level::Error, "Failed to pause/resume source: {e}"),
+        }
+    }
 }
 
 pub fn spawn(
@@ -140,13 +172,22 @@ pub fn spawn(
         loop {
             match (shutdown_request, rts.control_rx.try_recv()) {
                 (None, Ok(message)) => match message {
-                    Message::Play => rts.paused = false,
-                    Message::Pause => rts.paused = true,
+                    Message::Play => {
+                        rts.paused = false;
+                        rts.set_source_paused(false);
+                    }
+                    Message::Pause => {
+                        rts.paused = true;
+                        rts.set_source_paused(true);
+                    }
                     Message::Stop => {
                         rts.paused = true;
+                        rts.set_source_paused(false);
                         rts.renderer.reset_sequence();
                     }
                     Message::Shutdown => {
+                        // a paused source wouldn't ask for the reply that disconnects it
+                        rts.set_source_paused(false);
                         shutdown_request = Some(std::time::Instant::now());
                     }
                     Message::LoadSampleSet(loader) => {
@@ -183,9 +224,27 @@ pub fn spawn(
                 },
             }
 
-            match (shutdown_request, rts.pull_request_rx.try_recv()) {
+            // requests made just before pausing are left queued until playing again, so
+            // that no silence is inserted into the paused sequence
+            let pull_request = match (shutdown_request, rts.source_paused) {
+                (None, true) => Err(std::sync::mpsc::TryRecvError::Empty),
+                _ => rts.pull_request_rx.try_recv(),
+            };
+
+            match (shutdown_request, pull_request) {
                 (None, Ok(req)) => {
-                    let num_vacant = rts.buffer_tx.vacant_len();
+                    if req.underruns > rts.underruns {
+                        log::log!(
+                            log::Level::Warn,
+                            "Drum sequence playback ran out of audio {} time(s)",
+                            req.underruns - rts.underruns
+                        );
+
+                        rts.underruns = req.underruns;
+                    }
+
+                    let num_vacant = (req.frames.get() * rts.channels)
+                        .min(rts.buffer_tx.vacant_len() / rts.channels * rts.channels);
 
                     if !rts.paused {
                         let (_, events) = rts
@@ -200,7 +259,7 @@ pub fn spawn(
                             }
                         }
                     } else {
-                        // TODO: add pausing on the audiothread side?
+                        // stopped, the source keeps playing silence
                         rts.buffer[..num_vacant].fill(0.0f32);
                     }
 
@@ -209,7 +268,7 @@ pub fn spawn(
                     match req
                         .response_tx
                         .send(audiothread::PulledSourcePullReply::FramesProvided(
-                            num_vacant.into(),
+                            (num_vacant / rts.channels).into(),
                         )) {
                         Ok(_) => (),
                         Err(SendError(_)) => {
@@ -315,9 +374,9 @@ mod tests {
         set.add_with_hash(ch.clone(), "ch".to_string());
         set.add_with_hash(sd.clone(), "sd".to_string());
 
-        set.set_label(&bd, DrumkitLabel::BassDrum).unwrap();
-        set.set_label(&ch, DrumkitLabel::ClosedHihat).unwrap();
-        set.set_label(&sd, DrumkitLabel::SnareDrum).unwrap();
+        set.set_label(bd, Some(DrumkitLabel::BassDrum)).unwrap();
+        set.set_label(ch, Some(DrumkitLabel::ClosedHihat)).unwrap();
+        set.set_label(sd, Some(DrumkitLabel::SnareDrum)).unwrap();
 
         (source, set)
     }
@@ -353,7 +412,8 @@ mod tests {
     #[ignore]
     fn test_drumkit_playback() {
         let (audiothread_tx, audiothread_rx) = channel::<audiothread::Message>();
-        audiothread::spawn(audiothread_rx, Some(audiothread::Opts::default()));
+        let _audiothread =
+            audiothread::spawn(audiothread_rx, Some(audiothread::Opts::default())).unwrap();
 
         let (control_tx, control_rx) = channel::<Message>();
         spawn(audiothread_tx.clone(), control_rx, None);
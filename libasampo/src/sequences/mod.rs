@@ -77,7 +77,7 @@ pub trait StepSequenceOps {
     fn len(&self) -> usize;
     fn is_empty(&self) -> bool;
     fn step_base_len(&self) -> NoteLength;
-    fn step(&self, n: usize) -> Option<StepInfo>;
+    fn step(&self, n: usize) -> Option<StepInfo<'_>>;
     fn set_timespec(&mut self, spec: TimeSpec);
     fn set_len(&mut self, len: usize);
     fn set_step_base_len(&mut self, len: NoteLength);
@@ -195,13 +195,13 @@ impl StepSequenceOps for DrumkitSequence {
         self.step_base_length
     }
 
-    fn step(&self, n: usize) -> Option<StepInfo> {
+    fn step(&self, n: usize) -> Option<StepInfo<'_>> {
         if let Some(triggers) = self.steps.get(n) {
             let base_len_in_samples = self
                 .timespec
                 .samples_per_note(48000.try_into().unwrap(), self.step_base_length);
 
-            let sign = if n % 2 == 0 { 1.0 } else { -1.0 };
+            let sign = if n.is_multiple_of(2) { 1.0 } else { -1.0 };
 
             Some(StepInfo {
                 length_in_samples_48k: base_len_in_samples
@@ -325,8 +325,6 @@ mod tests {
 
     use crate::prelude::{SampleOps, SourceOps};
 
-    use super::*;
-
     #[test]
     fn test_sample_from_json() {
         assert_eq!(sample!(json = r#"{ "uri": "abc123" }"#).uri(), "abc123");